@@ -1,16 +1,24 @@
+-- Initial schema, formerly init.sql.
+-- Written to be re-runnable so databases created from init.sql can enable RUN_MIGRATIONS as-is:
+-- CREATE ... IF NOT EXISTS leaves their tables alone, so section 8 adds what init.sql lacked.
+
 -- 1. Setup Extensions & Enums
 CREATE EXTENSION IF NOT EXISTS "pgcrypto";
 
-CREATE TYPE sub_status AS ENUM ('active', 'expired', 'banned');
+DO $$ BEGIN
+    CREATE TYPE sub_status AS ENUM ('active', 'expired', 'banned', 'cancelled');
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
 
 -- 2. Tariffs (The Plans: 1, 2, 3, 4)
 -- This maps directly to your Xray "userLevel"
-CREATE TABLE tariffs (
+CREATE TABLE IF NOT EXISTS tariffs (
     id              SMALLINT PRIMARY KEY, -- 1, 2, 3, 4
     name            TEXT NOT NULL,        -- "Start", "Pro", etc.
     price           NUMERIC(10, 2) NOT NULL,
     speed_limit_mbps INT NOT NULL,        -- 1, 7, 25, 50
-    xray_level      INT NOT NULL          -- Maps to Xray config userLevel
+    xray_level      INT NOT NULL,         -- Maps to Xray config userLevel
+    byte_limit      BIGINT                -- Traffic quota per subscription, NULL = unlimited
 );
 
 -- Seed the tariffs immediately so they exist for constraints
@@ -18,15 +26,19 @@ INSERT INTO tariffs (id, name, price, speed_limit_mbps, xray_level) VALUES
 (1, 'Basic', 29.00, 1, 1),
 (2, 'Standard', 79.00, 7, 2),
 (3, 'Pro', 279.00, 25, 3),
-(4, 'Ultra', 399.00, 50, 4);
+(4, 'Ultra', 399.00, 50, 4)
+ON CONFLICT (id) DO NOTHING;
 
 -- 3. Servers (Nodes)
-CREATE TABLE servers (
+CREATE TABLE IF NOT EXISTS servers (
     id             UUID PRIMARY KEY DEFAULT gen_random_uuid(),
     slug           TEXT NOT NULL UNIQUE,      -- e.g. "de-helsinki-1"
     ip_address     INET NOT NULL,             -- 1.2.3.4
     domain         TEXT NOT NULL,             -- "vpn1.example.com"
     api_secret     TEXT NOT NULL DEFAULT encode(gen_random_bytes(32), 'hex'),
+    -- Also accepted during rotation: add the new secret here, roll the agent,
+    -- then move it into api_secret and clear this list
+    extra_api_secrets TEXT[] NOT NULL DEFAULT '{}',
 
     -- Xray Connection Info
     api_port       INT NOT NULL DEFAULT 8080,
@@ -43,19 +55,20 @@ CREATE TABLE servers (
 );
 
 -- 4. Users (Telegram info)
-CREATE TABLE users (
+CREATE TABLE IF NOT EXISTS users (
     id          UUID PRIMARY KEY DEFAULT gen_random_uuid(),
     tg_id       BIGINT NOT NULL UNIQUE,
     username    TEXT,
     full_name   TEXT,
     language    VARCHAR(5) DEFAULT 'en',
     balance     NUMERIC(10, 2) DEFAULT 0.00,
+    is_active   BOOLEAN NOT NULL DEFAULT true, -- false = deleted/suspended, never synced to agents
     created_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
     updated_at  TIMESTAMPTZ NOT NULL DEFAULT now()
 );
 
 -- 5. Subscriptions (The Link between User, Server, and Tariff)
-CREATE TABLE subscriptions (
+CREATE TABLE IF NOT EXISTS subscriptions (
     id          UUID PRIMARY KEY DEFAULT gen_random_uuid(),
     user_id     UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
     server_id   UUID NOT NULL REFERENCES servers(id),
@@ -67,6 +80,7 @@ CREATE TABLE subscriptions (
     
     status      sub_status NOT NULL DEFAULT 'active',
     expire_date TIMESTAMPTZ NOT NULL,
+    notified_at TIMESTAMPTZ,             -- expiry webhook sent; cleared when the subscription is extended
     
     created_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
     updated_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
@@ -75,10 +89,19 @@ CREATE TABLE subscriptions (
     CONSTRAINT uq_xray_uuid UNIQUE (xray_uuid)
 );
 
+-- 5b. Usage (Traffic reported by proxy agents, accumulated per Xray UUID)
+CREATE TABLE IF NOT EXISTS usage (
+    xray_uuid   UUID PRIMARY KEY REFERENCES subscriptions(xray_uuid) ON DELETE CASCADE,
+    bytes_up    BIGINT NOT NULL DEFAULT 0,
+    bytes_down  BIGINT NOT NULL DEFAULT 0,
+    bytes_used  BIGINT GENERATED ALWAYS AS (bytes_up + bytes_down) STORED,
+    updated_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
 -- Indexes for performance
-CREATE INDEX idx_subs_user ON subscriptions(user_id);
-CREATE INDEX idx_subs_server ON subscriptions(server_id) WHERE status = 'active';
-CREATE INDEX idx_subs_expiry ON subscriptions(expire_date);
+CREATE INDEX IF NOT EXISTS idx_subs_user ON subscriptions(user_id);
+CREATE INDEX IF NOT EXISTS idx_subs_server ON subscriptions(server_id) WHERE status = 'active';
+CREATE INDEX IF NOT EXISTS idx_subs_expiry ON subscriptions(expire_date);
 
 -- 6. View: Server Load (Real-time Analytics)
 -- This answers your question: "How many users are on this server?"
@@ -104,6 +127,16 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
+DROP TRIGGER IF EXISTS users_updated_at ON users;
 CREATE TRIGGER users_updated_at BEFORE UPDATE ON users FOR EACH ROW EXECUTE PROCEDURE set_updated_at();
+DROP TRIGGER IF EXISTS servers_updated_at ON servers;
 CREATE TRIGGER servers_updated_at BEFORE UPDATE ON servers FOR EACH ROW EXECUTE PROCEDURE set_updated_at();
-CREATE TRIGGER subscriptions_updated_at BEFORE UPDATE ON subscriptions FOR EACH ROW EXECUTE PROCEDURE set_updated_at();
\ No newline at end of file
+DROP TRIGGER IF EXISTS subscriptions_updated_at ON subscriptions;
+CREATE TRIGGER subscriptions_updated_at BEFORE UPDATE ON subscriptions FOR EACH ROW EXECUTE PROCEDURE set_updated_at();
+
+-- 8. Upgrade from init.sql: columns and enum values the tables above have that it didn't create
+ALTER TYPE sub_status ADD VALUE IF NOT EXISTS 'cancelled';
+ALTER TABLE tariffs ADD COLUMN IF NOT EXISTS byte_limit BIGINT;
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS extra_api_secrets TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_active BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE subscriptions ADD COLUMN IF NOT EXISTS notified_at TIMESTAMPTZ;
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Last heartbeat from each server's proxy_agent
+CREATE TABLE agents (
+    server_id      UUID PRIMARY KEY REFERENCES servers(id) ON DELETE CASCADE,
+    version        TEXT NOT NULL,
+    active_count   INT NOT NULL,              -- users the agent has provisioned in Xray
+    expected_count INT NOT NULL,              -- users /sync would hand it at heartbeat time
+    last_sync_at   TIMESTAMPTZ,               -- agent's last successful sync, NULL if none yet
+    last_seen_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
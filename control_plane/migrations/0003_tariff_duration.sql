@@ -0,0 +1,2 @@
+-- Default length of a purchase on this tariff, managed through /api/v1/plans
+ALTER TABLE tariffs ADD COLUMN IF NOT EXISTS duration_days INT NOT NULL DEFAULT 30;
@@ -0,0 +1,6 @@
+-- Operator requests to have an agent remove and re-add a subscription's Xray user.
+-- Handed to the agent in sync responses; rows older than the hint window are pruned on insert.
+CREATE TABLE resync_requests (
+    xray_uuid    UUID PRIMARY KEY REFERENCES subscriptions(xray_uuid) ON DELETE CASCADE,
+    requested_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,8 @@
+-- Client-side VLESS parameters, so the control plane can hand out complete vless:// links.
+-- Defaults match what the bot has been hardcoding (Reality + Vision over TCP on 443).
+ALTER TABLE servers
+    ADD COLUMN IF NOT EXISTS vless_port  INT NOT NULL DEFAULT 443,
+    ADD COLUMN IF NOT EXISTS sni         TEXT,                        -- NULL = use domain
+    ADD COLUMN IF NOT EXISTS fingerprint TEXT NOT NULL DEFAULT 'chrome',
+    ADD COLUMN IF NOT EXISTS flow        TEXT NOT NULL DEFAULT 'xtls-rprx-vision',
+    ADD COLUMN IF NOT EXISTS transport   TEXT NOT NULL DEFAULT 'tcp';
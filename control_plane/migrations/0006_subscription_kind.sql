@@ -0,0 +1,12 @@
+-- Trial vs paid, for conversion analytics and for servers that should carry only one kind.
+-- Existing rows become 'paid'; trials granted before this migration can't be told apart.
+DO $$ BEGIN
+    CREATE TYPE sub_kind AS ENUM ('trial', 'paid');
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
+
+ALTER TABLE subscriptions ADD COLUMN IF NOT EXISTS kind sub_kind NOT NULL DEFAULT 'paid';
+
+-- NULL: the server carries both kinds. Otherwise sync hands its agent only that kind and
+-- create_user doesn't place other kinds there.
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS only_kind sub_kind;
@@ -0,0 +1,8 @@
+-- Devices (distinct source IPs) each subscription is connected from, as last reported by the
+-- agent of its server. Each report replaces that server's rows. Groundwork for per-plan device
+-- limits; nothing enforces them yet.
+CREATE TABLE user_online (
+    xray_uuid    UUID PRIMARY KEY REFERENCES subscriptions(xray_uuid) ON DELETE CASCADE,
+    online_count INT NOT NULL,
+    reported_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,3 @@
+-- Which Xray instance on the server carries users of this tariff, by the name the agent gives it
+-- in XRAY_INSTANCES. NULL: the agent's first (default) instance.
+ALTER TABLE tariffs ADD COLUMN IF NOT EXISTS xray_target TEXT;
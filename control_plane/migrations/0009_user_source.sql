@@ -0,0 +1,3 @@
+-- Where a user signed up from, for attribution: 'telegram' (the bot), 'admin' (admin panel)
+-- or 'import' (bulk import). Set on creation only; existing users count as 'telegram'.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS source TEXT NOT NULL DEFAULT 'telegram';
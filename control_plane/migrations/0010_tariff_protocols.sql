@@ -0,0 +1,4 @@
+-- Protocols a tariff's users may be provisioned on ('vless', 'vmess', 'trojan', 'shadowsocks').
+-- Agents that declare their protocols only get users whose tariff shares one with them.
+-- NULL: any protocol.
+ALTER TABLE tariffs ADD COLUMN IF NOT EXISTS protocols TEXT[];
@@ -0,0 +1,4 @@
+-- Inbounds (by tag, as in the agent's XRAY_INBOUND_TAG) a tariff's users are provisioned on.
+-- Taking a tag out moves users off that inbound only, e.g. while retiring an old one.
+-- NULL: every inbound the agent has.
+ALTER TABLE tariffs ADD COLUMN IF NOT EXISTS inbound_tags TEXT[];
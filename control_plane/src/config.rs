@@ -0,0 +1,222 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::info;
+
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+const DEFAULT_FREE_TRIAL_MINUTES: i32 = 10;
+const DEFAULT_FREE_TRIAL_TARIFF_ID: i16 = 1;
+const DEFAULT_CREATE_USER_RATE_PER_MIN: u32 = 30;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_DB_MAX_CONN: u32 = 20;
+const DEFAULT_DB_MIN_CONN: u32 = 2;
+const DEFAULT_DB_ACQUIRE_TIMEOUT_SECS: u64 = 5;
+// Startup waits this long for Postgres, retrying with backoff from 1s up to the max
+const DEFAULT_DB_CONNECT_MAX_WAIT_SECS: u64 = 60;
+const DEFAULT_DB_CONNECT_MAX_BACKOFF_SECS: u64 = 10;
+const DEFAULT_EXPIRY_CHECK_INTERVAL_SECS: u64 = 60;
+// Used when neither BIND_ADDR nor CONTROL_PLANE_URL names a port
+const DEFAULT_PORT: u16 = 3333;
+
+// Everything the control plane reads from the environment, parsed and checked once at startup
+// so a typo fails the start instead of quietly falling back to a default.
+pub struct Config {
+    pub database_url: String,
+    pub bind_addr: SocketAddr,
+    // Guards the /api/v1 admin operations; None disables them
+    pub admin_token: Option<String>,
+    // Length of the free trial granted by create_user; 0 disables trials
+    pub trial_minutes: i32,
+    pub trial_tariff_id: i16,
+    // Minutes past expire_date that users keep access; 0 cuts them off at expiry.
+    // Applies to all plans alike, there is no per-plan override yet.
+    pub grace_minutes: i32,
+    pub sync_signing_key: Option<Vec<u8>>,
+    // Origins allowed to call /api/v1 from a browser; empty keeps CORS off
+    pub cors_origins: Vec<String>,
+    // 0 disables the per-IP limit on POST /api/v1/users
+    pub create_user_rate: u32,
+    pub idempotency_ttl: Duration,
+    pub shutdown_grace: Duration,
+    // Where lapsed subscriptions are announced (e.g. the Telegram bot); None disables the scan
+    pub expiry_webhook_url: Option<String>,
+    pub expiry_check_interval: Duration,
+    // Announces new users, e.g. so the bot can send their config after a bulk import
+    pub user_created_webhook_url: Option<String>,
+    pub db_max_conn: u32,
+    pub db_min_conn: u32,
+    pub db_acquire_timeout: Duration,
+    // 0 tries once and exits on failure
+    pub db_connect_max_wait: Duration,
+    pub db_connect_max_backoff: Duration,
+    // Off by default so a misconfigured replica can't alter a shared production schema
+    pub run_migrations: bool,
+}
+
+// Unset or empty is None; anything else has to parse
+fn var<T: FromStr>(name: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("{} {:?} is invalid: {}", name, v, e)),
+        _ => Ok(None),
+    }
+}
+
+fn var_or<T: FromStr>(name: &str, default: T) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    Ok(var(name)?.unwrap_or(default))
+}
+
+fn secs_or(name: &str, default: u64) -> Result<Duration, String> {
+    var_or(name, default).map(Duration::from_secs)
+}
+
+fn string(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+// Just scheme and host: webhook URLs often carry a bot token in the path
+fn redact_url(raw: &str) -> String {
+    match url::Url::parse(raw) {
+        Ok(url) => format!("{}://{}/...", url.scheme(), url.host_str().unwrap_or_default()),
+        Err(_) => "<invalid url>".to_string(),
+    }
+}
+
+fn set_or_unset<T>(value: &Option<T>) -> &'static str {
+    if value.is_some() {
+        "set"
+    } else {
+        "unset"
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let database_url = string("DATABASE_URL").ok_or("DATABASE_URL must be set")?;
+        let control_plane_url = string("CONTROL_PLANE_URL").ok_or("CONTROL_PLANE_URL must be set")?;
+        // BIND_ADDR picks interface and port; without it we listen on all interfaces on the
+        // port from CONTROL_PLANE_URL
+        let bind_addr = match var::<SocketAddr>("BIND_ADDR")? {
+            Some(addr) => addr,
+            None => SocketAddr::from((
+                [0, 0, 0, 0],
+                control_plane_url
+                    .split(':')
+                    .next_back()
+                    .and_then(|p| p.trim_end_matches('/').parse().ok())
+                    .unwrap_or(DEFAULT_PORT),
+            )),
+        };
+
+        let trial_minutes: i32 = var_or("FREE_TRIAL_MINUTES", DEFAULT_FREE_TRIAL_MINUTES)?;
+        if trial_minutes < 0 {
+            return Err("FREE_TRIAL_MINUTES must not be negative".into());
+        }
+        let grace_minutes: i32 = var_or("GRACE_MINUTES", 0)?;
+        if grace_minutes < 0 {
+            return Err("GRACE_MINUTES must not be negative".into());
+        }
+
+        let cors_origins: Vec<String> = std::env::var("CORS_ALLOWED_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|o| o.trim().to_string())
+            .filter(|o| !o.is_empty())
+            .collect();
+
+        let expiry_webhook_url = string("EXPIRY_WEBHOOK_URL");
+        let user_created_webhook_url = string("USER_CREATED_WEBHOOK_URL");
+        for (name, url) in [
+            ("EXPIRY_WEBHOOK_URL", &expiry_webhook_url),
+            ("USER_CREATED_WEBHOOK_URL", &user_created_webhook_url),
+        ] {
+            if let Some(url) = url {
+                url::Url::parse(url).map_err(|e| format!("{} is not a valid URL: {}", name, e))?;
+            }
+        }
+
+        let db_max_conn: u32 = var_or("DB_MAX_CONN", DEFAULT_DB_MAX_CONN)?.max(1);
+        let db_min_conn: u32 = var_or("DB_MIN_CONN", DEFAULT_DB_MIN_CONN)?.min(db_max_conn);
+
+        Ok(Self {
+            database_url,
+            bind_addr,
+            admin_token: string("ADMIN_TOKEN"),
+            trial_minutes,
+            trial_tariff_id: var_or("FREE_TRIAL_PLAN_ID", DEFAULT_FREE_TRIAL_TARIFF_ID)?,
+            grace_minutes,
+            sync_signing_key: string("SYNC_SIGNING_KEY").map(String::into_bytes),
+            cors_origins,
+            create_user_rate: var_or("CREATE_USER_RATE_PER_MIN", DEFAULT_CREATE_USER_RATE_PER_MIN)?,
+            idempotency_ttl: secs_or("IDEMPOTENCY_TTL_SECS", DEFAULT_IDEMPOTENCY_TTL_SECS)?,
+            shutdown_grace: secs_or("SHUTDOWN_GRACE_SECS", DEFAULT_SHUTDOWN_GRACE_SECS)?,
+            expiry_webhook_url,
+            expiry_check_interval: secs_or("EXPIRY_CHECK_INTERVAL_SECS", DEFAULT_EXPIRY_CHECK_INTERVAL_SECS)?
+                .max(Duration::from_secs(1)),
+            user_created_webhook_url,
+            db_max_conn,
+            db_min_conn,
+            db_acquire_timeout: secs_or("DB_ACQUIRE_TIMEOUT_SECS", DEFAULT_DB_ACQUIRE_TIMEOUT_SECS)?,
+            db_connect_max_wait: secs_or("DB_CONNECT_MAX_WAIT_SECS", DEFAULT_DB_CONNECT_MAX_WAIT_SECS)?,
+            db_connect_max_backoff: secs_or("DB_CONNECT_MAX_BACKOFF_SECS", DEFAULT_DB_CONNECT_MAX_BACKOFF_SECS)?
+                .max(Duration::from_secs(1)),
+            run_migrations: var_or("RUN_MIGRATIONS", false)?,
+        })
+    }
+
+    // The effective settings, with secrets reduced to whether they are set
+    pub fn log(&self) {
+        let database = url::Url::parse(&self.database_url).map_or_else(
+            |_| "<invalid url>".to_string(),
+            |mut url| {
+                if url.password().is_some() {
+                    let _ = url.set_password(Some("***"));
+                }
+                url.to_string()
+            },
+        );
+        info!(
+            "Config: bind_addr={}, database={}, run_migrations={}, admin_token={}, sync_signing_key={}",
+            self.bind_addr,
+            database,
+            self.run_migrations,
+            set_or_unset(&self.admin_token),
+            set_or_unset(&self.sync_signing_key)
+        );
+        info!(
+            "Config: trial_minutes={}, trial_plan_id={}, grace_minutes={}, create_user_rate_per_min={}, \
+             idempotency_ttl={:?}, shutdown_grace={:?}, cors_origins={:?}",
+            self.trial_minutes,
+            self.trial_tariff_id,
+            self.grace_minutes,
+            self.create_user_rate,
+            self.idempotency_ttl,
+            self.shutdown_grace,
+            self.cors_origins
+        );
+        info!(
+            "Config: expiry_webhook={} every {:?}, user_created_webhook={}",
+            self.expiry_webhook_url.as_deref().map_or("unset".to_string(), redact_url),
+            self.expiry_check_interval,
+            self.user_created_webhook_url.as_deref().map_or("unset".to_string(), redact_url)
+        );
+        info!(
+            "Config: DB pool max_connections={}, min_connections={}, acquire_timeout={:?}, \
+             connect_max_wait={:?}, connect_max_backoff={:?}",
+            self.db_max_conn,
+            self.db_min_conn,
+            self.db_acquire_timeout,
+            self.db_connect_max_wait,
+            self.db_connect_max_backoff
+        );
+    }
+}
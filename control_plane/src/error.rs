@@ -0,0 +1,69 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::time::Duration;
+
+// Error returned by every handler, rendered as {"error": {"code": "not_found", "message": "..."}}.
+// Messages are fixed strings written for API clients; details stay in our logs.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: &'static str,
+    // WWW-Authenticate value for 401s
+    challenge: Option<&'static str>,
+    // Retry-After seconds for 429s and 503s
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: &'static str) -> Self {
+        Self {
+            status,
+            message,
+            challenge: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn with_challenge(mut self, challenge: &'static str) -> Self {
+        self.challenge = Some(challenge);
+        self
+    }
+
+    // Rounded up to whole seconds, so clients never come back too early
+    pub fn with_retry_after(mut self, wait: Duration) -> Self {
+        self.retry_after = Some(wait.as_secs() + u64::from(wait.subsec_nanos() > 0));
+        self
+    }
+
+    // Stable machine-readable form of the status, e.g. 404 -> "not_found"
+    fn code(&self) -> String {
+        self.status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_ascii_lowercase()
+            .replace([' ', '-'], "_")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "error": {
+                "code": self.code(),
+                "message": self.message,
+            }
+        });
+        let mut res = (self.status, Json(body)).into_response();
+        if let Some(challenge) = self.challenge {
+            res.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+        }
+        if let Some(secs) = self.retry_after {
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        res
+    }
+}
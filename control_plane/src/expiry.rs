@@ -0,0 +1,144 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::subscriptions::SubscriptionStatus;
+use crate::{db_error, require_admin, AppState};
+
+// Subscriptions handled per scan; the rest are picked up on the next tick
+const EXPIRY_BATCH_SIZE: i64 = 100;
+const DEFAULT_EXPIRING_WITHIN_HOURS: i32 = 24;
+// 30 days; reminders further out than that aren't useful
+const MAX_EXPIRING_WITHIN_HOURS: i32 = 720;
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ExpiryEvent {
+    #[serde(skip)]
+    id: Uuid,
+    tg_id: i64,
+    uuid: Uuid,
+    expire_date: DateTime<Utc>,
+}
+
+async fn post_event(client: &reqwest::Client, url: &str, event: &ExpiryEvent) -> reqwest::Result<()> {
+    client.post(url).json(event).send().await?.error_for_status()?;
+    Ok(())
+}
+
+// One pass: every lapsed subscription not yet notified gets a webhook, then notified_at is set.
+// Stops at the first webhook failure so the rest are retried on the next pass instead of hammering it.
+async fn notify_expired(pool: &sqlx::PgPool, client: &reqwest::Client, url: &str) {
+    let events = match sqlx::query_as::<_, ExpiryEvent>(
+        r#"
+        SELECT s.id, usr.tg_id, s.xray_uuid AS uuid, s.expire_date
+        FROM subscriptions s
+        JOIN users usr ON usr.id = s.user_id
+        WHERE s.notified_at IS NULL
+          AND s.expire_date <= now()
+          AND (s.status = $2 OR s.status = $3)
+        ORDER BY s.expire_date
+        LIMIT $1
+        "#,
+    )
+    .bind(EXPIRY_BATCH_SIZE)
+    .bind(SubscriptionStatus::Active)
+    .bind(SubscriptionStatus::Expired)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(events) => events,
+        Err(e) => {
+            tracing::error!("expiry scan db error: {}", e);
+            return;
+        }
+    };
+
+    for event in events {
+        if let Err(e) = post_event(client, url, &event).await {
+            metrics::counter!("expiry_webhook_total", "result" => "error").increment(1);
+            tracing::warn!("Expiry webhook for {} failed, will retry: {}", event.uuid, e);
+            return;
+        }
+        metrics::counter!("expiry_webhook_total", "result" => "ok").increment(1);
+
+        if let Err(e) = sqlx::query("UPDATE subscriptions SET notified_at = now() WHERE id = $1")
+            .bind(event.id)
+            .execute(pool)
+            .await
+        {
+            tracing::error!("mark notified db error: {}", e);
+            return;
+        }
+        tracing::info!("Sent expiry notification for tg_id {} ({})", event.tg_id, event.uuid);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExpiringParams {
+    within_hours: Option<i32>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct ExpiringSubscription {
+    tg_id: i64,
+    uuid: Uuid,
+    expire_date: DateTime<Utc>,
+}
+
+// Active subscriptions running out within the next `within_hours`, soonest first, so the bot
+// can send renewal reminders in one batch. Already-lapsed ones are the expiry webhook's job.
+pub async fn list_expiring(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<ExpiringParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let within_hours = params.within_hours.unwrap_or(DEFAULT_EXPIRING_WITHIN_HOURS);
+    if within_hours <= 0 || within_hours > MAX_EXPIRING_WITHIN_HOURS {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "within_hours must be between 1 and 720"));
+    }
+
+    // A plain range on expire_date, so idx_subs_expiry can serve it
+    let expiring = sqlx::query_as::<_, ExpiringSubscription>(
+        r#"
+        SELECT usr.tg_id, s.xray_uuid AS uuid, s.expire_date
+        FROM subscriptions s
+        JOIN users usr ON usr.id = s.user_id
+        WHERE s.expire_date > now()
+          AND s.expire_date <= now() + make_interval(hours => $1)
+          AND s.status = $2
+          AND usr.is_active
+        ORDER BY s.expire_date
+        "#,
+    )
+    .bind(within_hours)
+    .bind(SubscriptionStatus::Active)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error("list expiring"))?;
+
+    Ok(Json(expiring))
+}
+
+// Background task: periodically report subscriptions that lapsed since the last pass
+pub async fn notify_expired_loop(pool: sqlx::PgPool, webhook_url: String, interval: Duration) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("reqwest client");
+
+    loop {
+        tokio::time::sleep(interval).await;
+        notify_expired(&pool, &client, &webhook_url).await;
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Past this many stored keys, expired entries are dropped on the next insert
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Entry<T> {
+    value: T,
+    stored_at: Instant,
+}
+
+// Responses remembered by client-supplied Idempotency-Key for `ttl`. Per process, like the rate
+// limiter: a retry that lands on another replica runs again, which the upserts behind it tolerate.
+pub struct IdempotencyCache<T> {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry<T>>>,
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<T> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|e| e.stored_at.elapsed() < self.ttl)
+            .map(|e| e.value.clone())
+    }
+
+    pub fn insert(&self, key: String, value: T) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() > PRUNE_THRESHOLD {
+            entries.retain(|_, e| e.stored_at.elapsed() < self.ttl);
+        }
+        entries.insert(
+            key,
+            Entry {
+                value,
+                stored_at: Instant::now(),
+            },
+        );
+    }
+}
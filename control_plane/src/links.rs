@@ -0,0 +1,103 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use url::Url;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::{lookup_error, require_admin, AppState};
+
+// Everything a vless:// link needs: the subscription's identity plus its server's template
+#[derive(sqlx::FromRow)]
+struct LinkParams {
+    xray_uuid: Uuid,
+    host: String,
+    domain: String,
+    public_key: String,
+    short_ids: Vec<String>,
+    vless_port: i32,
+    sni: Option<String>,
+    fingerprint: String,
+    flow: String,
+    transport: String,
+    tariff_name: String,
+}
+
+#[derive(Serialize)]
+pub struct LinkResponse {
+    uuid: Uuid,
+    link: String,
+}
+
+// vless://uuid@host:port?...#VPN_<tariff>, the same shape the bot used to assemble itself.
+// The first short id is used; an empty sid is valid when the server allows it.
+fn vless_uri(p: &LinkParams) -> Result<String, url::ParseError> {
+    let host = if p.host.contains(':') {
+        format!("[{}]", p.host)
+    } else {
+        p.host.clone()
+    };
+    let mut uri = Url::parse(&format!("vless://{}@{}:{}", p.xray_uuid, host, p.vless_port))?;
+    uri.query_pairs_mut()
+        .append_pair("security", "reality")
+        .append_pair("encryption", "none")
+        .append_pair("pbk", &p.public_key)
+        .append_pair("fp", &p.fingerprint)
+        .append_pair("type", &p.transport)
+        .append_pair("flow", &p.flow)
+        .append_pair("sni", p.sni.as_deref().unwrap_or(&p.domain))
+        .append_pair("sid", p.short_ids.first().map(String::as_str).unwrap_or(""));
+    uri.set_fragment(Some(&format!("VPN_{}", p.tariff_name)));
+    Ok(uri.into())
+}
+
+// The vless:// link of a subscription, from its server's link settings. Takes any executor so
+// provisioning can build the link inside its transaction.
+pub async fn link_for<'e>(executor: impl sqlx::PgExecutor<'e>, xray_uuid: Uuid) -> Result<String, ApiError> {
+    let params = sqlx::query_as::<_, LinkParams>(
+        r#"
+        SELECT
+            s.xray_uuid,
+            host(srv.ip_address) AS host,
+            srv.domain,
+            srv.public_key,
+            srv.short_ids,
+            srv.vless_port,
+            srv.sni,
+            srv.fingerprint,
+            srv.flow,
+            srv.transport,
+            t.name AS tariff_name
+        FROM subscriptions s
+        JOIN servers srv ON srv.id = s.server_id
+        JOIN tariffs t ON t.id = s.tariff_id
+        WHERE s.xray_uuid = $1
+        "#,
+    )
+    .bind(xray_uuid)
+    .fetch_one(executor)
+    .await
+    .map_err(lookup_error("subscription link", "subscription not found"))?;
+
+    vless_uri(&params).map_err(|e| {
+        tracing::error!("Bad link settings for subscription {}: {}", xray_uuid, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "invalid server link settings")
+    })
+}
+
+// Ready-to-import client link for a subscription, built from its server's link settings
+pub async fn subscription_link(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(xray_uuid): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let link = link_for(&state.pool, xray_uuid).await?;
+    Ok(Json(LinkResponse { uuid: xray_uuid, link }))
+}
@@ -1,19 +1,65 @@
+mod expiry;
+mod config;
+mod error;
+mod idempotency;
+mod links;
+mod plans;
+mod provision;
+mod rate_limit;
+mod servers;
+mod subscriptions;
+#[cfg(test)]
+mod test_util;
+mod user_webhook;
+mod users;
+
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::get,
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
     Json, Router,
 };
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Digest;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPoolOptions;
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
 use tracing::info;
 use uuid::Uuid;
 
+use error::ApiError;
+use subscriptions::SubscriptionStatus;
+
 #[derive(Clone)]
 struct AppState {
     pool: sqlx::PgPool,
+    metrics: PrometheusHandle,
+    // Guards the /api/v1 admin operations; None disables them
+    admin_token: Option<String>,
+    // Length of the free trial granted by create_user; 0 disables trials
+    trial_minutes: i32,
+    // tariffs.id the trial subscription is created on
+    trial_tariff_id: i16,
+    // Per-IP limit on POST /api/v1/users; None when CREATE_USER_RATE_PER_MIN=0
+    create_user_limiter: Option<Arc<rate_limit::RateLimiter>>,
+    // create_user responses by Idempotency-Key, with the tg_id they were for
+    create_user_replies: Arc<idempotency::IdempotencyCache<(i64, users::CreateUserResponse)>>,
+    // Same for provision
+    provision_replies: Arc<idempotency::IdempotencyCache<(i64, provision::ProvisionResponse)>>,
+    // SYNC_SIGNING_KEY; when set, sync responses carry an HMAC in X-Sync-Signature
+    sync_signing_key: Option<Vec<u8>>,
+    // USER_CREATED_WEBHOOK_URL; None when unset
+    user_created_webhook: Option<user_webhook::UserCreatedWebhook>,
+    // GRACE_MINUTES: how long past expire_date agents keep serving a subscription, for every plan
+    grace_minutes: i32,
 }
 
 // The response now includes the Tariff Level (1, 2, 3, 4)
@@ -22,94 +68,1634 @@ struct UserConfig {
     uuid: String,
     level: u32,
     email: String,
+    // Tariff's xray_target: the named Xray instance that should carry this user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    // Tariff's inbound_tags: the inbounds this user belongs on; absent means all of them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    // Only sent with ?detailed=true, so agents can drop users right at expiry.
+    // This is when access ends, i.e. expire_date plus the grace period.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_date: Option<DateTime<Utc>>,
+}
+
+// Row shape shared by /sync and the added half of /sync/delta
+struct SyncRow {
+    xray_uuid: Uuid,
+    xray_level: i32,
+    email: String,
+    target: Option<String>,
+    tags: Option<Vec<String>>,
+    expire_date: DateTime<Utc>,
+}
+
+impl SyncRow {
+    fn into_config(self, detailed: bool) -> UserConfig {
+        UserConfig {
+            uuid: self.xray_uuid.to_string(),
+            level: self.xray_level as u32,
+            email: self.email,
+            target: self.target,
+            tags: self.tags,
+            expire_date: detailed.then_some(self.expire_date),
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct SyncResponse {
+    // Format version negotiated through Accept-Version; absent for v1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
     users: Vec<UserConfig>,
+    // Starting point for /sync/delta
+    cursor: DateTime<Utc>,
+    // The database clock expiry is judged by, for the agent to compare its own against
+    server_time: DateTime<Utc>,
+    // Emails the agent should remove from Xray and add again (operator-requested repair)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    resync: Vec<String>,
+}
+
+// Crate version and the commit it was built from (build.rs), for /version and the startup log
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_SHA: &str = env!("GIT_SHA");
+// A hung database must not hang the healthcheck
+const HEALTH_DB_TIMEOUT_MS: u64 = 2000;
+// Retry-After on a not-ready /readyz
+const READYZ_RETRY_AFTER_SECS: u64 = 5;
+
+// Cursors older than this are rejected so a long-offline agent does a full sync instead
+const DELTA_MAX_CURSOR_AGE_SECS: i64 = 3600;
+// Rows committed just before the previous cursor was taken may carry an older updated_at,
+// so every delta window overlaps the previous one by this much. Re-sent adds are harmless.
+const DELTA_OVERLAP_SECS: i64 = 5;
+// A full sync also carries resync requests this recent, for agents that just (re)started
+const RESYNC_HINT_WINDOW_SECS: i64 = 600;
+// /sync/stream sends the body in chunks of about this size, at most this many buffered at once
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+const STREAM_CHANNEL_CHUNKS: usize = 4;
+// Carries the delta cursor on a 304 from /sync, which has no body to put it in
+const SYNC_CURSOR_HEADER: HeaderName = HeaderName::from_static("x-sync-cursor");
+// Newest sync format we send. v1 is the original body without a version field; v2 adds
+// `version` and is otherwise the same. Fields are only ever added within a version, so agents
+// ignore what they don't know; a new version is for changes they must not misread.
+const SYNC_FORMAT_VERSION: u32 = 2;
+// Newest sync format the agent understands; without it the agent gets v1
+const ACCEPT_VERSION_HEADER: &str = "accept-version";
+// Protocols an agent's inbounds speak, e.g. "vless,vmess"; narrows sync to compatible tariffs
+const AGENT_PROTOCOLS_HEADER: &str = "x-agent-protocols";
+
+#[derive(Deserialize)]
+struct SyncParams {
+    #[serde(default)]
+    detailed: bool,
+}
+
+#[derive(Deserialize)]
+struct DeltaParams {
+    since: String,
+    #[serde(default)]
+    detailed: bool,
+}
+
+#[derive(Serialize)]
+struct DeltaResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    added: Vec<UserConfig>,
+    // Emails, since that is what the agent removes users by
+    removed: Vec<String>,
+    // Pass back as `since` on the next delta call
+    cursor: DateTime<Utc>,
+    server_time: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    resync: Vec<String>,
+}
+
+// Constant-time so response timing doesn't reveal how much of a guessed secret was right.
+// Empty values never match, so a blank api_secret can't authorize blank requests.
+fn secret_matches(candidate: &str, expected: &str) -> bool {
+    !candidate.is_empty()
+        && !expected.is_empty()
+        && bool::from(candidate.as_bytes().ct_eq(expected.as_bytes()))
+}
+
+// `Authorization: Bearer <secret>` wins over X-Server-Secret when both are sent;
+// the custom header stays for agents behind proxies that do forward it
+fn server_secret(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .filter(|token| !token.is_empty());
+    bearer.or_else(|| {
+        headers
+            .get("X-Server-Secret")
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    })
+}
+
+// Serialize a sync payload and, with SYNC_SIGNING_KEY set, sign the exact bytes sent:
+// X-Sync-Signature is the hex HMAC-SHA256 of the body. Agents holding the key reject unsigned
+// or mismatching responses, so a spoofed control plane can't push users to them.
+fn signed_json<T: Serialize>(state: &AppState, payload: &T) -> Response {
+    let body = match serde_json::to_vec(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("sync serialize error: {}", e);
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "serialize error").into_response();
+        }
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    if let Some(key) = &state.sync_signing_key {
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(&body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        headers.insert(
+            "X-Sync-Signature",
+            header::HeaderValue::from_str(&signature).expect("hex is a valid header value"),
+        );
+    }
+    (headers, body).into_response()
+}
+
+// Fingerprint of what an agent applies from a full sync: the users and resync hints, but not the
+// cursor, which moves on every call. Users must already be in a stable order.
+fn sync_etag(users: &[UserConfig], resync: &[String]) -> String {
+    let bytes = serde_json::to_vec(&(users, resync)).expect("sync payload serializes");
+    format!("\"{}\"", hex::encode(&sha2::Sha256::digest(&bytes)[..16]))
+}
+
+// Whether If-None-Match names `etag` (a list of tags or "*" also counts)
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').map(str::trim).any(|tag| tag == etag || tag == "*"))
+}
+
+// Identify the calling server by its secret (Bearer token or X-Server-Secret header).
+// No secret at all is a 401 with a Bearer challenge; one that matches no server is a 403.
+// Servers with a blank api_secret can't be authenticated as (see secret_matches).
+async fn authenticate_server(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<Uuid, ApiError> {
+    let secret = server_secret(headers)
+        .ok_or(ApiError::new(StatusCode::UNAUTHORIZED, "missing secret").with_challenge("Bearer"))
+        .inspect_err(|_| metrics::counter!("sync_unauthorized_total", "reason" => "missing").increment(1))?;
+
+    // Compare in Rust rather than `WHERE api_secret = $1`, which isn't constant-time.
+    // Every row is checked so the match position doesn't show in the timing either.
+    // A server is matched by its api_secret or any of its extra_api_secrets (rotation in progress)
+    let servers = sqlx::query_as::<_, (Uuid, String, Vec<String>)>(
+        "SELECT id, api_secret, extra_api_secrets FROM servers",
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error("authenticate"))?;
+
+    let mut matched: Option<Uuid> = None;
+    for (id, api_secret, extra_api_secrets) in servers {
+        for accepted in std::iter::once(&api_secret).chain(extra_api_secrets.iter()) {
+            if secret_matches(secret, accepted) {
+                matched = Some(id);
+            }
+        }
+    }
+    matched
+        .ok_or(ApiError::new(StatusCode::FORBIDDEN, "invalid secret"))
+        .inspect_err(|_| metrics::counter!("sync_unauthorized_total", "reason" => "invalid").increment(1))
+}
+
+// Handler-side mapping for sqlx errors: logged with `what` and answered as an opaque 500
+fn db_error(what: &'static str) -> impl Fn(sqlx::Error) -> ApiError {
+    move |e| {
+        tracing::error!("{} db error: {}", what, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+    }
+}
+
+// Same for single-row lookups via fetch_one, where no row is the caller's 404 rather than our 500
+fn lookup_error(what: &'static str, not_found: &'static str) -> impl Fn(sqlx::Error) -> ApiError {
+    move |e| match e {
+        sqlx::Error::RowNotFound => ApiError::new(StatusCode::NOT_FOUND, not_found),
+        e => db_error(what)(e),
+    }
+}
+
+// Admin/bot operations authenticate with the shared ADMIN_TOKEN in X-Admin-Token
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let expected = state
+        .admin_token
+        .as_deref()
+        .ok_or(ApiError::new(StatusCode::FORBIDDEN, "admin api disabled"))?;
+    let token = headers
+        .get("X-Admin-Token")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(ApiError::new(StatusCode::UNAUTHORIZED, "missing admin token"))?;
+    if !secret_matches(token, expected) {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid admin token"));
+    }
+    Ok(())
+}
+
+// The sync format to answer in: the agent's Accept-Version capped at ours. None is v1, which
+// every agent understands and which carries no version field.
+fn sync_version(headers: &HeaderMap) -> Result<Option<u32>, ApiError> {
+    let Some(value) = headers.get(ACCEPT_VERSION_HEADER) else {
+        return Ok(None);
+    };
+    let requested: u32 = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|v| *v >= 1)
+        .ok_or(ApiError::new(StatusCode::BAD_REQUEST, "Accept-Version must be a positive integer"))?;
+    Ok(Some(requested.min(SYNC_FORMAT_VERSION)).filter(|v| *v > 1))
+}
+
+// The agent's declared protocols, lowercased; None (no header) means it takes every user.
+// Compared against tariffs.protocols, where NULL also means any.
+fn agent_protocols(headers: &HeaderMap) -> Option<Vec<String>> {
+    let protocols: Vec<String> = headers
+        .get(AGENT_PROTOCOLS_HEADER)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(|p| p.trim().to_ascii_lowercase())
+        .filter(|p| !p.is_empty())
+        .collect();
+    (!protocols.is_empty()).then_some(protocols)
+}
+
+// The subscriptions sync hands out, across all servers: $1 is SubscriptionStatus::Active, $2 the
+// grace period in minutes and $3 the agent's protocols (NULL for any). Queries counting that set
+// append their own conditions; active_users repeats it because query_as! needs a literal.
+const SYNCED_SUBSCRIPTIONS: &str = r#"
+        FROM subscriptions s
+        JOIN tariffs t ON s.tariff_id = t.id
+        JOIN users usr ON usr.id = s.user_id
+        JOIN servers srv ON srv.id = s.server_id
+        LEFT JOIN usage u ON u.xray_uuid = s.xray_uuid
+        WHERE s.status = $1
+          AND usr.is_active
+          AND s.expire_date + make_interval(mins => $2) > now()
+          AND (t.byte_limit IS NULL OR COALESCE(u.bytes_used, 0) < t.byte_limit)
+          AND (srv.only_kind IS NULL OR s.kind = srv.only_kind)
+          AND ($3::text[] IS NULL OR t.protocols IS NULL OR t.protocols && $3)
+"#;
+
+// Users a full sync hands to the server's agent. Tariffs are joined for the xray_level; metered
+// tariffs (non-NULL byte_limit) drop users once their usage reaches the limit, servers with
+// only_kind set get only trials or only paid subscriptions, expired subscriptions stay in
+// for the grace period ($3 minutes), and agents that declared protocols ($4) only get tariffs
+// allowing one of them.
+fn active_users(
+    server_id: Uuid,
+    grace_minutes: i32,
+    protocols: Option<Vec<String>>,
+) -> sqlx::query::Map<
+    'static,
+    sqlx::Postgres,
+    impl FnMut(sqlx::postgres::PgRow) -> Result<SyncRow, sqlx::Error> + Send,
+    sqlx::postgres::PgArguments,
+> {
+    sqlx::query_as!(
+        SyncRow,
+        r#"
+        SELECT 
+            s.xray_uuid, 
+            t.xray_level, 
+            s.email,
+            t.xray_target AS target,
+            t.inbound_tags AS tags,
+            s.expire_date + make_interval(mins => $3) AS "expire_date!"
+        FROM subscriptions s
+        JOIN tariffs t ON s.tariff_id = t.id
+        JOIN users usr ON usr.id = s.user_id
+        JOIN servers srv ON srv.id = s.server_id
+        LEFT JOIN usage u ON u.xray_uuid = s.xray_uuid
+        WHERE s.server_id = $1 
+          AND s.status = $2
+          AND usr.is_active
+          AND s.expire_date + make_interval(mins => $3) > now()
+          AND (t.byte_limit IS NULL OR COALESCE(u.bytes_used, 0) < t.byte_limit)
+          AND (srv.only_kind IS NULL OR s.kind = srv.only_kind)
+          AND ($4::text[] IS NULL OR t.protocols IS NULL OR t.protocols && $4)
+        "#,
+        server_id,
+        SubscriptionStatus::Active as SubscriptionStatus,
+        grace_minutes,
+        protocols as Option<Vec<String>>,
+    )
 }
 
 async fn sync(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    println!("Received sync request.");
-    // 1. Get Secret
-    println!("Headers: {:?}", headers);
-    let secret = headers
-        .get("X-Server-Secret")
-        .and_then(|v| v.to_str().ok())
-        .ok_or((StatusCode::UNAUTHORIZED, "missing secret"))?;
+    Query(params): Query<SyncParams>,
+) -> Result<Response, ApiError> {
+    metrics::counter!("sync_requests_total", "kind" => "full").increment(1);
+    // 1. Identify Server by Secret
+    let server_id = authenticate_server(&state, &headers).await?;
+    let version = sync_version(&headers)?;
+
+    // Taken before the snapshot so nothing changed during the query is skipped by the next delta.
+    // It is also the server_time agents check their clock against.
+    let cursor: DateTime<Utc> = sqlx::query_scalar("SELECT now()")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error("sync"))?;
 
-    // 2. Identify Server by Secret
-    let server_id: Uuid = sqlx::query_scalar("SELECT id FROM servers WHERE api_secret = $1")
-        .bind(secret)
-        .fetch_optional(&state.pool)
+    // 2. Fetch Active Users assigned ONLY to THIS server
+    let query_started = std::time::Instant::now();
+    let rows = active_users(server_id, state.grace_minutes, agent_protocols(&headers))
+        .fetch_all(&state.pool)
         .await
-        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
-        .ok_or((StatusCode::UNAUTHORIZED, "invalid secret"))?;
+        .map_err(db_error("sync"))?;
+    metrics::histogram!("sync_query_seconds", "kind" => "full").record(query_started.elapsed().as_secs_f64());
 
-    // 3. Fetch Active Users assigned ONLY to THIS server
-    // We join 'subscriptions' and 'tariffs' to get the xray_level
-    let rows = sqlx::query_as::<_, (Uuid, i32, String)>(
+    let mut users: Vec<UserConfig> = rows.into_iter().map(|row| row.into_config(params.detailed)).collect();
+    users.sort_unstable_by(|a, b| a.email.cmp(&b.email));
+
+    let resync = resync_hints(&state, server_id, cursor - chrono::Duration::seconds(RESYNC_HINT_WINDOW_SECS)).await?;
+
+    // Unchanged since the agent's last full sync: skip the body, but hand out a fresh cursor so
+    // the agent can carry on with deltas. There is no body to sign; a forged 304 can only withhold
+    // changes, which an attacker able to answer for us could do anyway.
+    let etag = sync_etag(&users, &resync);
+    let etag_header = header::HeaderValue::from_str(&etag).expect("hex is a valid header value");
+    if etag_matches(&headers, &etag) {
+        metrics::counter!("sync_not_modified_total").increment(1);
+        info!("Server {} sync: unchanged, {} active users", server_id, users.len());
+        let cursor = header::HeaderValue::from_str(&cursor.to_rfc3339()).expect("RFC 3339 is a valid header value");
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag_header), (SYNC_CURSOR_HEADER, cursor)]).into_response());
+    }
+
+    info!("Server {} sync: {} active users", server_id, users.len());
+    let mut res = signed_json(&state, &SyncResponse { version, users, cursor, server_time: cursor, resync });
+    res.headers_mut().insert(header::ETAG, etag_header);
+    Ok(res)
+}
+
+#[derive(Serialize)]
+struct StreamTrailer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    cursor: DateTime<Utc>,
+    server_time: DateTime<Utc>,
+    resync: Vec<String>,
+    // User lines sent before this one
+    count: u64,
+}
+
+// NDJSON variant of /sync for servers with very many users: one UserConfig per line, written
+// straight from the database cursor so memory stays flat. The last line is {"end": trailer}
+// with the delta cursor and resync hints; with SYNC_SIGNING_KEY it is followed by
+// {"signature": hex HMAC-SHA256 of every byte before it}. An agent that never sees the trailer
+// must treat the sync as failed rather than apply a truncated list.
+async fn sync_stream(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<SyncParams>,
+) -> Result<Response, ApiError> {
+    metrics::counter!("sync_requests_total", "kind" => "stream").increment(1);
+    let server_id = authenticate_server(&state, &headers).await?;
+    let version = sync_version(&headers)?;
+
+    let cursor: DateTime<Utc> = sqlx::query_scalar("SELECT now()")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error("sync stream"))?;
+    let resync = resync_hints(&state, server_id, cursor - chrono::Duration::seconds(RESYNC_HINT_WINDOW_SECS)).await?;
+    let trailer = StreamTrailer { version, cursor, server_time: cursor, resync, count: 0 };
+
+    let (tx, rx) = tokio::sync::mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    let protocols = agent_protocols(&headers);
+    tokio::spawn(write_sync_stream(state, server_id, protocols, params.detailed, trailer, tx));
+    let body = axum::body::Body::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+// Producer side of sync_stream. The bounded channel holds back the query while the agent reads
+// slowly. A database error aborts the body, which the agent sees as a missing trailer.
+async fn write_sync_stream(
+    state: Arc<AppState>,
+    server_id: Uuid,
+    protocols: Option<Vec<String>>,
+    detailed: bool,
+    mut trailer: StreamTrailer,
+    tx: tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>,
+) {
+    let mut mac = state
+        .sync_signing_key
+        .as_ref()
+        .map(|key| Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length"));
+    let mut rows = active_users(server_id, state.grace_minutes, protocols).fetch(&state.pool);
+    let mut chunk = Vec::with_capacity(STREAM_CHUNK_BYTES);
+    loop {
+        match futures::TryStreamExt::try_next(&mut rows).await {
+            Ok(Some(row)) => {
+                serde_json::to_writer(&mut chunk, &row.into_config(detailed)).expect("user serializes");
+                chunk.push(b'\n');
+                trailer.count += 1;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                tracing::error!("sync stream db error: {}", e);
+                let _ = tx.send(Err(std::io::Error::other("db error"))).await;
+                return;
+            }
+        }
+        if chunk.len() >= STREAM_CHUNK_BYTES {
+            if let Some(mac) = &mut mac {
+                mac.update(&chunk);
+            }
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(STREAM_CHUNK_BYTES));
+            if tx.send(Ok(full)).await.is_err() {
+                // Agent hung up
+                return;
+            }
+        }
+    }
+
+    serde_json::to_writer(&mut chunk, &serde_json::json!({ "end": &trailer })).expect("trailer serializes");
+    chunk.push(b'\n');
+    if let Some(mut mac) = mac {
+        mac.update(&chunk);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        serde_json::to_writer(&mut chunk, &serde_json::json!({ "signature": signature })).expect("signature serializes");
+        chunk.push(b'\n');
+    }
+    let _ = tx.send(Ok(chunk)).await;
+    info!("Server {} stream sync: {} active users", server_id, trailer.count);
+}
+
+// Only what changed on this server since the agent's last cursor.
+// Answers 410 when the cursor is unusable; the agent then falls back to a full /sync.
+async fn sync_delta(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<DeltaParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    metrics::counter!("sync_requests_total", "kind" => "delta").increment(1);
+    let server_id = authenticate_server(&state, &headers).await?;
+    let version = sync_version(&headers)?;
+
+    let since = DateTime::parse_from_rfc3339(&params.since)
+        .map_err(|_| ApiError::new(StatusCode::GONE, "invalid cursor"))?
+        .with_timezone(&Utc);
+
+    let cursor: DateTime<Utc> = sqlx::query_scalar("SELECT now()")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error("sync delta"))?;
+
+    if since > cursor || (cursor - since).num_seconds() > DELTA_MAX_CURSOR_AGE_SECS {
+        return Err(ApiError::new(StatusCode::GONE, "cursor expired"));
+    }
+    let window_start = since - chrono::Duration::seconds(DELTA_OVERLAP_SECS);
+    let protocols = agent_protocols(&headers);
+    let query_started = std::time::Instant::now();
+
+    // Subscriptions touched since the cursor that are (still) active here
+    let added_rows = sqlx::query_as!(
+        SyncRow,
         r#"
         SELECT 
             s.xray_uuid, 
             t.xray_level, 
-            s.email 
+            s.email,
+            t.xray_target AS target,
+            t.inbound_tags AS tags,
+            s.expire_date + make_interval(mins => $5) AS "expire_date!"
         FROM subscriptions s
         JOIN tariffs t ON s.tariff_id = t.id
+        JOIN users usr ON usr.id = s.user_id
+        JOIN servers srv ON srv.id = s.server_id
+        LEFT JOIN usage u ON u.xray_uuid = s.xray_uuid
         WHERE s.server_id = $1 
-          AND s.status = 'active'
-          AND s.expire_date > now()
+          AND s.status = $4
+          AND usr.is_active
+          AND s.expire_date + make_interval(mins => $5) > $3
+          AND (t.byte_limit IS NULL OR COALESCE(u.bytes_used, 0) < t.byte_limit)
+          AND (srv.only_kind IS NULL OR s.kind = srv.only_kind)
+          AND ($6::text[] IS NULL OR t.protocols IS NULL OR t.protocols && $6)
+          AND (s.updated_at >= $2 OR usr.updated_at >= $2 OR srv.updated_at >= $2)
+        "#,
+        server_id,
+        window_start,
+        cursor,
+        SubscriptionStatus::Active as SubscriptionStatus,
+        state.grace_minutes,
+        protocols.clone() as Option<Vec<String>>,
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error("sync delta"))?;
+
+    // Subscriptions that stopped being active: status changed, expired (grace included) inside the window,
+    // ran over quota with usage reported inside the window, or no longer match the server's only_kind
+    // or the agent's protocols (e.g. moved to a tariff the agent can't serve)
+    let removed: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT s.email
+        FROM subscriptions s
+        JOIN tariffs t ON s.tariff_id = t.id
+        JOIN users usr ON usr.id = s.user_id
+        JOIN servers srv ON srv.id = s.server_id
+        LEFT JOIN usage u ON u.xray_uuid = s.xray_uuid
+        WHERE s.server_id = $1
+          AND (
+            s.status <> $4
+            OR NOT usr.is_active
+            OR s.expire_date + make_interval(mins => $5) <= $3
+            OR (t.byte_limit IS NOT NULL AND COALESCE(u.bytes_used, 0) >= t.byte_limit)
+            OR s.kind <> COALESCE(srv.only_kind, s.kind)
+            OR NOT ($6::text[] IS NULL OR t.protocols IS NULL OR t.protocols && $6)
+          )
+          AND (
+            s.updated_at >= $2
+            OR usr.updated_at >= $2
+            OR srv.updated_at >= $2
+            OR (s.expire_date + make_interval(mins => $5) > $2 AND s.expire_date + make_interval(mins => $5) <= $3)
+            OR u.updated_at >= $2
+          )
         "#,
     )
     .bind(server_id)
+    .bind(window_start)
+    .bind(cursor)
+    .bind(SubscriptionStatus::Active)
+    .bind(state.grace_minutes)
+    .bind(&protocols)
     .fetch_all(&state.pool)
     .await
-    .map_err(|e| {
-        tracing::error!("sync db error: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "db error")
-    })?;
+    .map_err(db_error("sync delta"))?;
 
-    let users: Vec<UserConfig> = rows
+    metrics::histogram!("sync_query_seconds", "kind" => "delta").record(query_started.elapsed().as_secs_f64());
+
+    let added: Vec<UserConfig> = added_rows
         .into_iter()
-        .map(|(uuid, level, email)| UserConfig {
-            uuid: uuid.to_string(),
-            level: level as u32,
-            email,
-        })
+        .map(|row| row.into_config(params.detailed))
         .collect();
 
-    info!("Server {} sync: {} active users", server_id, users.len());
-    Ok(Json(SyncResponse { users }))
+    info!(
+        "Server {} delta sync: {} added, {} removed",
+        server_id,
+        added.len(),
+        removed.len()
+    );
+    let resync = resync_hints(&state, server_id, window_start).await?;
+
+    Ok(signed_json(&state, &DeltaResponse { version, added, removed, cursor, server_time: cursor, resync }))
+}
+
+// Emails on this server with a resync requested since `since`. The request also bumped the
+// subscription's updated_at, so an active one comes back in the same delta's `added`.
+async fn resync_hints(
+    state: &AppState,
+    server_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<Vec<String>, ApiError> {
+    sqlx::query_scalar(
+        r#"
+        SELECT s.email
+        FROM resync_requests r
+        JOIN subscriptions s ON s.xray_uuid = r.xray_uuid
+        WHERE s.server_id = $1 AND r.requested_at >= $2
+        "#,
+    )
+    .bind(server_id)
+    .bind(since)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error("resync hints"))
+}
+
+// Prometheus text exposition of everything recorded through the `metrics` macros
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    state.metrics.render()
+}
+
+// Liveness: the process is up and serving. Deliberately doesn't touch the DB,
+// so a database outage doesn't get every replica restarted.
+async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// Which build this replica runs, for matching behavior to releases
+async fn version() -> impl IntoResponse {
+    Json(serde_json::json!({ "version": VERSION, "git_sha": GIT_SHA }))
+}
+
+// Readiness: 503 while Postgres is unreachable or every pool connection is busy,
+// so orchestrators stop routing traffic here without restarting us
+async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let size = state.pool.size();
+    let idle = state.pool.num_idle();
+    // Without idle connections we can still open new ones until max_connections
+    let saturated = idle == 0 && size >= state.pool.options().get_max_connections();
+
+    let status = if saturated {
+        "pool_saturated"
+    } else {
+        let ping = sqlx::query("SELECT 1").execute(&state.pool);
+        match tokio::time::timeout(std::time::Duration::from_millis(HEALTH_DB_TIMEOUT_MS), ping).await {
+            Ok(Ok(_)) => "ok",
+            Ok(Err(e)) => {
+                tracing::warn!("readyz db error: {}", e);
+                "db_unavailable"
+            }
+            Err(_) => {
+                tracing::warn!("readyz db ping timed out");
+                "db_timeout"
+            }
+        }
+    };
+
+    let body = serde_json::json!({
+        "status": status,
+        "pool_size": size,
+        "pool_idle": idle,
+    });
+    if status == "ok" {
+        (StatusCode::OK, Json(body)).into_response()
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, READYZ_RETRY_AFTER_SECS.to_string())],
+            Json(body),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct UserTraffic {
+    email: String,
+    uplink: i64,
+    downlink: i64,
+}
+
+#[derive(Deserialize)]
+struct UsageReport {
+    users: Vec<UserTraffic>,
+}
+
+// Agents report traffic since their previous report; we add it onto the running totals
+async fn report_usage(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(report): Json<UsageReport>,
+) -> Result<impl IntoResponse, ApiError> {
+    let server_id = authenticate_server(&state, &headers).await?;
+
+    let mut emails = Vec::with_capacity(report.users.len());
+    let mut ups = Vec::with_capacity(report.users.len());
+    let mut downs = Vec::with_capacity(report.users.len());
+    for u in report.users {
+        emails.push(u.email);
+        ups.push(u.uplink.max(0));
+        downs.push(u.downlink.max(0));
+    }
+
+    // Emails only resolve against subscriptions on the reporting server
+    let result = sqlx::query(
+        r#"
+        INSERT INTO usage (xray_uuid, bytes_up, bytes_down)
+        SELECT s.xray_uuid, r.up, r.down
+        FROM UNNEST($2::text[], $3::bigint[], $4::bigint[]) AS r(email, up, down)
+        JOIN subscriptions s ON s.email = r.email AND s.server_id = $1
+        ON CONFLICT (xray_uuid) DO UPDATE SET
+            bytes_up = usage.bytes_up + EXCLUDED.bytes_up,
+            bytes_down = usage.bytes_down + EXCLUDED.bytes_down,
+            updated_at = now()
+        "#,
+    )
+    .bind(server_id)
+    .bind(&emails)
+    .bind(&ups)
+    .bind(&downs)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error("usage"))?;
+
+    info!(
+        "Server {} usage: {} of {} reported users recorded",
+        server_id,
+        result.rows_affected(),
+        emails.len()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct UserOnline {
+    email: String,
+    online: i32,
+}
+
+#[derive(Deserialize)]
+struct OnlineReport {
+    users: Vec<UserOnline>,
+}
+
+// Agents report every user currently connected and from how many IPs. A report is a snapshot,
+// so users left out of it are no longer online on that server.
+async fn report_online(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(report): Json<OnlineReport>,
+) -> Result<impl IntoResponse, ApiError> {
+    let server_id = authenticate_server(&state, &headers).await?;
+
+    let (emails, counts): (Vec<String>, Vec<i32>) = report.users.into_iter().map(|u| (u.email, u.online.max(0))).unzip();
+
+    let mut tx = state.pool.begin().await.map_err(db_error("online"))?;
+    sqlx::query(
+        r#"
+        DELETE FROM user_online o
+        USING subscriptions s
+        WHERE s.xray_uuid = o.xray_uuid AND s.server_id = $1
+        "#,
+    )
+    .bind(server_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error("online"))?;
+
+    // Emails only resolve against subscriptions on the reporting server
+    let result = sqlx::query(
+        r#"
+        INSERT INTO user_online (xray_uuid, online_count)
+        SELECT s.xray_uuid, r.online
+        FROM UNNEST($2::text[], $3::int[]) AS r(email, online)
+        JOIN subscriptions s ON s.email = r.email AND s.server_id = $1
+        ON CONFLICT (xray_uuid) DO UPDATE SET
+            online_count = EXCLUDED.online_count,
+            reported_at = now()
+        "#,
+    )
+    .bind(server_id)
+    .bind(&emails)
+    .bind(&counts)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error("online"))?;
+    tx.commit().await.map_err(db_error("online"))?;
+
+    info!(
+        "Server {} online: {} of {} reported users recorded",
+        server_id,
+        result.rows_affected(),
+        emails.len()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct Heartbeat {
+    version: String,
+    active_count: i32,
+    last_sync_at: Option<DateTime<Utc>>,
+}
+
+// Agents periodically report how many users they actually have in Xray. We store that next to
+// the count /sync would currently hand them, so a drifting agent shows up in logs and metrics.
+async fn heartbeat(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(hb): Json<Heartbeat>,
+) -> Result<impl IntoResponse, ApiError> {
+    let server_id = authenticate_server(&state, &headers).await?;
+
+    let expected_count: i32 = sqlx::query_scalar(&format!(
+        r#"
+        INSERT INTO agents (server_id, version, active_count, expected_count, last_sync_at, last_seen_at)
+        SELECT $4, $5, $6, COUNT(*)::int, $7, now()
+        {SYNCED_SUBSCRIPTIONS}
+          AND s.server_id = $4
+        ON CONFLICT (server_id) DO UPDATE SET
+            version = EXCLUDED.version,
+            active_count = EXCLUDED.active_count,
+            expected_count = EXCLUDED.expected_count,
+            last_sync_at = EXCLUDED.last_sync_at,
+            last_seen_at = EXCLUDED.last_seen_at
+        RETURNING expected_count
+        "#
+    ))
+    .bind(SubscriptionStatus::Active)
+    .bind(state.grace_minutes)
+    .bind(agent_protocols(&headers))
+    .bind(server_id)
+    .bind(&hb.version)
+    .bind(hb.active_count)
+    .bind(hb.last_sync_at)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error("heartbeat"))?;
+
+    let drift = hb.active_count - expected_count;
+    metrics::gauge!("agent_user_drift", "server" => server_id.to_string()).set(drift as f64);
+    // Changes waiting for the agent's next sync cause brief drift; the gauge shows if it persists
+    if drift != 0 {
+        tracing::warn!(
+            "Server {} agent {} has {} users provisioned, expected {}",
+            server_id, hb.version, hb.active_count, expected_count
+        );
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct Stats {
+    active_users: i64,
+    total_users: i64,
+    active_subscriptions: i64,
+    expiring_within_24h: i64,
+}
+
+// Fleet-wide counts for dashboards. "Active" is the set sync hands out, summed over all servers,
+// so subscriptions in their grace period count; "expiring" means leaving that set within 24h.
+async fn stats(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let stats = sqlx::query_as::<_, Stats>(&format!(
+        r#"
+        SELECT
+            COUNT(DISTINCT s.user_id) AS active_users,
+            (SELECT COUNT(*) FROM users) AS total_users,
+            COUNT(*) AS active_subscriptions,
+            COUNT(*) FILTER (
+                WHERE s.expire_date + make_interval(mins => $2) <= now() + interval '24 hours'
+            ) AS expiring_within_24h
+        {SYNCED_SUBSCRIPTIONS}
+        "#
+    ))
+    .bind(SubscriptionStatus::Active)
+    .bind(state.grace_minutes)
+    .bind(None::<Vec<String>>)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error("stats"))?;
+
+    Ok(Json(stats))
+}
+
+// CORS for browser-based admin panels. Only explicitly listed origins are allowed; no credentials
+// mode is needed since auth travels in headers (X-Admin-Token / Authorization).
+fn cors_layer(origins: &[String]) -> Result<Option<CorsLayer>, Box<dyn std::error::Error>> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+    let origins = origins
+        .iter()
+        .map(|o| header::HeaderValue::from_str(o))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "CORS_ALLOWED_ORIGINS contains an invalid origin")?;
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                HeaderName::from_static("x-admin-token"),
+                HeaderName::from_static("idempotency-key"),
+            ])
+            .max_age(std::time::Duration::from_secs(600)),
+    ))
+}
+
+// Every route with its middleware; main serves it, tests call it directly
+fn router(state: Arc<AppState>, cors: Option<CorsLayer>) -> Router {
+    // Public API for the bot and admin frontends; the only part CORS may open up
+    let mut api_v1 = Router::new()
+        // GET takes a Telegram id, DELETE a users.id UUID; axum needs one param name per segment
+        .route(
+            "/api/v1/users",
+            get(users::list_users).merge(post(users::create_user).layer(
+                middleware::from_fn_with_state(state.clone(), rate_limit::limit_by_ip),
+            )),
+        )
+        .route("/api/v1/users/bulk", post(users::create_users_bulk))
+        .route("/api/v1/users/:id", get(users::get_user).delete(users::delete_user))
+        .route("/api/v1/users/:id/suspend", post(users::suspend_user))
+        .route("/api/v1/users/:id/unsuspend", post(users::unsuspend_user))
+        .route("/api/v1/provision", post(provision::provision))
+        .route("/api/v1/plans", get(plans::list_plans).post(plans::create_plan))
+        .route("/api/v1/plans/:id", put(plans::update_plan))
+        .route("/api/v1/servers/:id", get(servers::get_server))
+        .route("/api/v1/servers/:id/reality", put(servers::set_reality))
+        .route("/api/v1/subscriptions/extend", post(subscriptions::extend_subscription))
+        .route("/api/v1/subscriptions/:uuid/link", get(links::subscription_link));
+    if let Some(cors) = cors {
+        api_v1 = api_v1.layer(cors);
+    }
+
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version))
+        .route("/metrics", get(metrics_handler))
+        .route("/api/internal/sync", get(sync))
+        .route("/api/internal/sync/delta", get(sync_delta))
+        .route("/api/internal/sync/stream", get(sync_stream))
+        .route("/api/internal/usage", post(report_usage))
+        .route("/api/internal/online", post(report_online))
+        .route("/api/internal/heartbeat", post(heartbeat))
+        .route("/api/internal/resync/:uuid", post(subscriptions::request_resync))
+        .route("/api/internal/stats", get(stats))
+        .route("/api/internal/expiring", get(expiry::list_expiring))
+        .merge(api_v1)
+        .with_state(state)
+        // The last layer runs first: keep or assign X-Request-Id, open a span with it, echo it back
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(|req: &axum::extract::Request| {
+            let request_id = req
+                .headers()
+                .get("x-request-id")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-");
+            tracing::info_span!("request", method = %req.method(), path = %req.uri().path(), request_id = %request_id)
+        }))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+// Resolves on the first SIGINT (Ctrl+C) or SIGTERM (docker stop / systemd)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, draining in-flight requests");
+}
+
+// Postgres often comes up after us (docker compose starts both at once), so the first connect is
+// retried with doubling backoff. Past `max_wait` we give up with the last error.
+async fn connect_db(
+    options: PgPoolOptions,
+    database_url: &str,
+    max_wait: std::time::Duration,
+    max_backoff: std::time::Duration,
+) -> Result<sqlx::PgPool, Box<dyn std::error::Error>> {
+    let started = std::time::Instant::now();
+    let mut delay = std::time::Duration::from_secs(1).min(max_backoff);
+    let mut attempts: u32 = 0;
+    loop {
+        attempts += 1;
+        match options.clone().connect(database_url).await {
+            Ok(pool) => {
+                if attempts > 1 {
+                    info!("Connected to the database after {} attempts", attempts);
+                }
+                return Ok(pool);
+            }
+            Err(e) if started.elapsed() + delay > max_wait => {
+                return Err(format!(
+                    "database unreachable after {} attempts over {}s (DB_CONNECT_MAX_WAIT_SECS): {}",
+                    attempts,
+                    started.elapsed().as_secs(),
+                    e
+                )
+                .into());
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Database connect attempt {} failed: {}. Retrying in {}s",
+                    attempts,
+                    e,
+                    delay.as_secs()
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(max_backoff);
+            }
+        }
+    }
+}
+
+// LOG_FORMAT=json switches to one JSON object per line for log shippers; RUST_LOG sets the level
+fn init_tracing() {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if std::env::var("LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json")) {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::args().skip(1).any(|a| a == "--version" || a == "-V") {
+        println!("control_plane {} ({})", VERSION, GIT_SHA);
+        return Ok(());
+    }
     dotenvy::dotenv().ok();
-    tracing_subscriber::fmt().init();
+    init_tracing();
+    info!("Starting Control Plane {} ({})", VERSION, GIT_SHA);
+
+    let config = config::Config::from_env()?;
+    config.log();
+    let cors = cors_layer(&config.cors_origins)?;
+    let user_created_webhook = config
+        .user_created_webhook_url
+        .clone()
+        .map(user_webhook::UserCreatedWebhook::new);
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let control_plane_url = std::env::var("CONTROL_PLANE_URL").expect("CONTROL_PLANE_URL must be set");
+    // min_connections keeps a few connections open so a cold-start burst doesn't pay for connecting
+    let pool_options = PgPoolOptions::new()
+        .max_connections(config.db_max_conn)
+        .min_connections(config.db_min_conn)
+        .acquire_timeout(config.db_acquire_timeout);
+    let pool = connect_db(
+        pool_options,
+        &config.database_url,
+        config.db_connect_max_wait,
+        config.db_connect_max_backoff,
+    )
+    .await?;
 
-    let pool = PgPoolOptions::new()
-        .max_connections(20)
-        .connect(&database_url)
-        .await?;
+    if config.run_migrations {
+        sqlx::migrate!().run(&pool).await?;
+        info!("Database migrations applied");
+    }
 
-    let state = Arc::new(AppState { pool });
+    let metrics = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("sync_query_seconds".to_string()),
+            &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0],
+        )?
+        .install_recorder()?;
+    // Without the exporter's own HTTP listener nobody drains histogram buffers for us
+    let upkeep = metrics.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            upkeep.run_upkeep();
+        }
+    });
 
-    let app = Router::new()
-        .route("/api/internal/sync", get(sync))
-        .with_state(state);
+    if let Some(url) = config.expiry_webhook_url.clone() {
+        info!("Expiry webhook enabled, checking every {:?}", config.expiry_check_interval);
+        tokio::spawn(expiry::notify_expired_loop(pool.clone(), url, config.expiry_check_interval));
+    }
 
-    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], control_plane_url
-        .split(':')
-        .last()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(3333)));
+    let state = Arc::new(AppState {
+        pool: pool.clone(),
+        metrics,
+        admin_token: config.admin_token.clone(),
+        trial_minutes: config.trial_minutes,
+        trial_tariff_id: config.trial_tariff_id,
+        create_user_limiter: (config.create_user_rate > 0)
+            .then(|| Arc::new(rate_limit::RateLimiter::new(config.create_user_rate))),
+        create_user_replies: Arc::new(idempotency::IdempotencyCache::new(config.idempotency_ttl)),
+        provision_replies: Arc::new(idempotency::IdempotencyCache::new(config.idempotency_ttl)),
+        sync_signing_key: config.sync_signing_key.clone(),
+        user_created_webhook,
+        grace_minutes: config.grace_minutes,
+    });
+
+    let app = router(state, cors);
+
+    let addr = config.bind_addr;
     info!("Control Plane listening on {}", addr);
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, app).await?;
+
+    // Stop accepting on the signal, then give in-flight handlers up to the grace period
+    let (signal_tx, signal_rx) = tokio::sync::oneshot::channel::<()>();
+    // Peer addresses are needed for per-IP rate limiting
+    let server = axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            let _ = signal_tx.send(());
+        });
+    let grace_deadline = async move {
+        if signal_rx.await.is_ok() {
+            tokio::time::sleep(config.shutdown_grace).await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    tokio::select! {
+        res = server => res?,
+        _ = grace_deadline => {
+            tracing::warn!("Grace period of {:?} elapsed, dropping remaining requests", config.shutdown_grace);
+        }
+    }
+
+    pool.close().await;
+    info!("Control Plane stopped");
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscriptions::SubscriptionKind;
+    use crate::test_util::{self, call, get, post_json, uuids};
+    use chrono::Duration;
+    use sqlx::PgPool;
+
+    const SECRET: &str = "agent-secret";
+
+    #[test]
+    fn secret_matches_rejects_empty_values() {
+        assert!(secret_matches("s3cret", "s3cret"));
+        assert!(!secret_matches("s3cret", "s3creT"));
+        assert!(!secret_matches("s3cre", "s3cret"));
+        // A blank SERVER_SECRET / api_secret must not authorize a blank header
+        assert!(!secret_matches("", ""));
+        assert!(!secret_matches("", "s3cret"));
+        assert!(!secret_matches("s3cret", ""));
+    }
+
+    #[test]
+    fn server_secret_prefers_bearer() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in pairs {
+                map.insert(*name, header::HeaderValue::from_static(value));
+            }
+            map
+        };
+        assert_eq!(server_secret(&headers(&[("authorization", "Bearer tok")])), Some("tok"));
+        assert_eq!(server_secret(&headers(&[("authorization", "bearer  tok ")])), Some("tok"));
+        assert_eq!(server_secret(&headers(&[("x-server-secret", "hdr")])), Some("hdr"));
+        assert_eq!(
+            server_secret(&headers(&[("authorization", "Bearer tok"), ("x-server-secret", "hdr")])),
+            Some("tok")
+        );
+        // Other schemes aren't ours; the custom header still counts
+        assert_eq!(
+            server_secret(&headers(&[("authorization", "Basic dXNlcg=="), ("x-server-secret", "hdr")])),
+            Some("hdr")
+        );
+        // An empty token is no token: fall back rather than fail on ""
+        assert_eq!(
+            server_secret(&headers(&[("authorization", "Bearer "), ("x-server-secret", "hdr")])),
+            Some("hdr")
+        );
+        assert_eq!(
+            server_secret(&headers(&[("authorization", "Bearer"), ("x-server-secret", "hdr")])),
+            Some("hdr")
+        );
+        assert_eq!(server_secret(&headers(&[("authorization", "Bearer  ")])), None);
+        assert_eq!(server_secret(&headers(&[("x-server-secret", " hdr ")])), Some("hdr"));
+        assert_eq!(server_secret(&headers(&[("x-server-secret", "")])), None);
+        assert_eq!(server_secret(&headers(&[])), None);
+    }
+
+    #[tokio::test]
+    async fn readyz_without_a_database_asks_to_retry() {
+        let state = test_util::state(test_util::unreachable_pool());
+
+        let res = call(&state, get("/readyz", &[])).await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers[header::RETRY_AFTER], READYZ_RETRY_AFTER_SECS.to_string().as_str());
+        assert_eq!(res.json()["status"], "db_unavailable");
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_accepts_either_secret_header(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        test_util::server(&pool, "de-1", SECRET).await;
+        let bearer = format!("Bearer {}", SECRET);
+
+        for headers in [
+            vec![("authorization", bearer.as_str())],
+            vec![("x-server-secret", SECRET)],
+            // Bearer wins, so a stale custom header behind a gateway doesn't matter
+            vec![("authorization", bearer.as_str()), ("x-server-secret", "stale")],
+        ] {
+            let res = call(&state, get("/api/internal/sync", &headers)).await;
+            assert_eq!(res.status, StatusCode::OK, "{:?}", headers);
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_returns_only_active_subscriptions(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        let (alice, bob) = (test_util::user(&pool, 1001).await, test_util::user(&pool, 1002).await);
+        let active = test_util::subscription(&pool, alice, server, 1, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await;
+        test_util::subscription(&pool, bob, server, 1, Utc::now() - Duration::minutes(1), SubscriptionKind::Paid).await;
+
+        let res = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res.headers.contains_key(header::ETAG));
+        let body = res.json();
+        assert_eq!(uuids(&body["users"]), vec![active.to_string()]);
+        assert_eq!(body["users"][0]["level"], 1);
+        assert!(body["cursor"].is_string());
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn servers_sync_only_their_own_subscriptions(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let servers = [
+            test_util::server(&pool, "de-1", "secret-de").await,
+            test_util::server(&pool, "fi-1", "secret-fi").await,
+        ];
+        let expires = Utc::now() + Duration::days(1);
+        let mut expected: [Vec<String>; 2] = Default::default();
+        for tg_id in 1001..1005 {
+            let user = test_util::user(&pool, tg_id).await;
+            let i = (tg_id % 2) as usize;
+            let sub = test_util::subscription(&pool, user, servers[i], 1, expires, SubscriptionKind::Paid).await;
+            expected[i].push(sub.to_string());
+        }
+
+        let mut synced: Vec<Vec<String>> = Vec::new();
+        for secret in ["secret-de", "secret-fi"] {
+            let body = call(&state, get("/api/internal/sync", &[("x-server-secret", secret)])).await.json();
+            synced.push(uuids(&body["users"]));
+        }
+        for list in &mut expected {
+            list.sort();
+        }
+        assert_eq!(synced, expected);
+        assert!(synced[0].iter().all(|uuid| !synced[1].contains(uuid)));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_filters_by_the_servers_only_kind(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        let expires = Utc::now() + Duration::days(1);
+        let (alice, bob) = (test_util::user(&pool, 1001).await, test_util::user(&pool, 1002).await);
+        let trial = test_util::subscription(&pool, alice, server, 1, expires, SubscriptionKind::Trial).await.to_string();
+        let paid = test_util::subscription(&pool, bob, server, 1, expires, SubscriptionKind::Paid).await.to_string();
+        let mut both = vec![trial.clone(), paid.clone()];
+        both.sort();
+
+        for (only_kind, expected) in [(None, both), (Some(SubscriptionKind::Trial), vec![trial]), (Some(SubscriptionKind::Paid), vec![paid])] {
+            sqlx::query("UPDATE servers SET only_kind = $1 WHERE id = $2")
+                .bind(only_kind)
+                .bind(server)
+                .execute(&pool)
+                .await
+                .unwrap();
+            let body = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await.json();
+            assert_eq!(uuids(&body["users"]), expected, "only_kind {:?}", only_kind);
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn stats_and_heartbeat_count_what_sync_hands_out(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        sqlx::query("UPDATE servers SET only_kind = $1 WHERE id = $2")
+            .bind(SubscriptionKind::Paid)
+            .bind(server)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let expires = Utc::now() + Duration::days(1);
+        let (alice, bob) = (test_util::user(&pool, 1001).await, test_util::user(&pool, 1002).await);
+        test_util::subscription(&pool, alice, server, 1, expires, SubscriptionKind::Trial).await;
+        let paid = test_util::subscription(&pool, bob, server, 1, expires, SubscriptionKind::Paid).await;
+
+        let body = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await.json();
+        assert_eq!(uuids(&body["users"]), vec![paid.to_string()]);
+
+        let stats = call(&state, get("/api/internal/stats", &[("x-admin-token", test_util::ADMIN_TOKEN)])).await.json();
+        assert_eq!(stats["active_users"], 1);
+        assert_eq!(stats["active_subscriptions"], 1);
+
+        let beat = serde_json::json!({ "version": "test", "active_count": 1 });
+        let res = call(&state, post_json("/api/internal/heartbeat", &[("x-server-secret", SECRET)], beat)).await;
+        assert_eq!(res.status, StatusCode::NO_CONTENT);
+        let expected: i32 = sqlx::query_scalar("SELECT expected_count FROM agents WHERE server_id = $1")
+            .bind(server)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(expected, 1);
+    }
+
+    #[test]
+    fn agent_protocols_are_normalized() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(agent_protocols(&headers), None);
+        headers.insert(AGENT_PROTOCOLS_HEADER, header::HeaderValue::from_static(" VLESS, trojan ,,"));
+        assert_eq!(agent_protocols(&headers), Some(vec!["vless".to_string(), "trojan".to_string()]));
+        // Declaring nothing is the same as not declaring
+        headers.insert(AGENT_PROTOCOLS_HEADER, header::HeaderValue::from_static(" , "));
+        assert_eq!(agent_protocols(&headers), None);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_only_hands_out_users_the_agents_protocols_can_serve(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        sqlx::query("UPDATE tariffs SET protocols = CASE id WHEN 1 THEN '{vless}'::text[] WHEN 2 THEN '{vmess}'::text[] END")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let expires = Utc::now() + Duration::days(1);
+        // Tariff 3 has no protocols set, so any agent may serve it
+        let mut subs = Vec::new();
+        for (tg_id, tariff_id) in [(1001, 1), (1002, 2), (1003, 3)] {
+            let user = test_util::user(&pool, tg_id).await;
+            subs.push(test_util::subscription(&pool, user, server, tariff_id, expires, SubscriptionKind::Paid).await.to_string());
+        }
+        let (vless, vmess, any) = (&subs[0], &subs[1], &subs[2]);
+
+        for (declared, expected) in [
+            (None, vec![vless, vmess, any]),
+            (Some("vmess"), vec![vmess, any]),
+            (Some("VLESS, trojan"), vec![vless, any]),
+            (Some("shadowsocks"), vec![any]),
+        ] {
+            let mut headers = vec![("x-server-secret", SECRET)];
+            headers.extend(declared.map(|p| (AGENT_PROTOCOLS_HEADER, p)));
+            let body = call(&state, get("/api/internal/sync", &headers)).await.json();
+            let mut expected: Vec<String> = expected.into_iter().cloned().collect();
+            expected.sort();
+            assert_eq!(uuids(&body["users"]), expected, "declared {:?}", declared);
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_omits_subscriptions_over_their_quota(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        sqlx::query("UPDATE tariffs SET byte_limit = 1000 WHERE id = 2").execute(&pool).await.unwrap();
+        let expires = Utc::now() + Duration::days(1);
+        let mut subs = Vec::new();
+        for (tg_id, tariff_id, bytes) in [(1001, 2, 1000), (1002, 2, 999), (1003, 1, i64::MAX / 2)] {
+            let user = test_util::user(&pool, tg_id).await;
+            let sub = test_util::subscription(&pool, user, server, tariff_id, expires, SubscriptionKind::Paid).await;
+            test_util::usage(&pool, sub, bytes).await;
+            subs.push(sub);
+        }
+        let (over, under, unmetered) = (subs[0], subs[1], subs[2]);
+
+        let body = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await.json();
+        let mut expected = vec![under.to_string(), unmetered.to_string()];
+        expected.sort();
+        assert_eq!(uuids(&body["users"]), expected);
+        assert!(!uuids(&body["users"]).contains(&over.to_string()));
+    }
+
+    #[tokio::test]
+    async fn sync_without_a_secret_is_challenged() {
+        let state = test_util::state(test_util::unreachable_pool());
+
+        for headers in [vec![], vec![("x-server-secret", "")], vec![("authorization", "Bearer ")]] {
+            let res = call(&state, get("/api/internal/sync", &headers)).await;
+            assert_eq!(res.status, StatusCode::UNAUTHORIZED, "{:?}", headers);
+            assert_eq!(res.headers[header::WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_rejects_an_unknown_secret(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        test_util::server(&pool, "de-1", SECRET).await;
+
+        let res = call(&state, get("/api/internal/sync", &[("x-server-secret", "not-the-secret")])).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert!(!res.headers.contains_key(header::WWW_AUTHENTICATE));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn a_blank_api_secret_authorizes_nobody(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        test_util::server(&pool, "de-1", "").await;
+
+        let blank = call(&state, get("/api/internal/sync", &[("x-server-secret", "")])).await;
+        assert_eq!(blank.status, StatusCode::UNAUTHORIZED);
+        let guess = call(&state, get("/api/internal/sync", &[("x-server-secret", "anything")])).await;
+        assert_eq!(guess.status, StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn unchanged_sync_answers_not_modified_with_a_fresh_cursor(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        let alice = test_util::user(&pool, 1001).await;
+        test_util::subscription(&pool, alice, server, 1, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await;
+
+        let first = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await;
+        let etag = first.headers[header::ETAG].to_str().unwrap().to_string();
+
+        let again = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET), ("if-none-match", &etag)])).await;
+        assert_eq!(again.status, StatusCode::NOT_MODIFIED);
+        assert!(again.body.is_empty());
+        assert_eq!(again.headers[header::ETAG], etag.as_str());
+        let cursor = again.headers[SYNC_CURSOR_HEADER].to_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(cursor).is_ok());
+
+        // A new user changes the tag
+        let bob = test_util::user(&pool, 1002).await;
+        test_util::subscription(&pool, bob, server, 1, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await;
+        let changed = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET), ("if-none-match", &etag)])).await;
+        assert_eq!(changed.status, StatusCode::OK);
+        assert_ne!(changed.headers[header::ETAG], etag.as_str());
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_bodies_are_signed_with_the_signing_key(pool: PgPool) {
+        let state = AppState {
+            sync_signing_key: Some(b"signing-key".to_vec()),
+            ..test_util::state(pool.clone())
+        };
+        test_util::server(&pool, "de-1", SECRET).await;
+
+        let res = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await;
+        let signature = hex::decode(res.headers["x-sync-signature"].to_str().unwrap()).unwrap();
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"signing-key").unwrap();
+        mac.update(&res.body);
+        assert!(mac.verify_slice(&signature).is_ok());
+
+        let unsigned = call(&test_util::state(pool), get("/api/internal/sync", &[("x-server-secret", SECRET)])).await;
+        assert!(!unsigned.headers.contains_key("x-sync-signature"));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn sync_stream_ends_with_a_trailer_and_signature(pool: PgPool) {
+        let state = AppState {
+            sync_signing_key: Some(b"signing-key".to_vec()),
+            ..test_util::state(pool.clone())
+        };
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        let mut expected = Vec::new();
+        for tg_id in 1001..1004 {
+            let user = test_util::user(&pool, tg_id).await;
+            expected.push(test_util::subscription(&pool, user, server, 1, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await.to_string());
+        }
+        expected.sort();
+
+        let res = call(&state, get("/api/internal/sync/stream", &[("x-server-secret", SECRET)])).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers[header::CONTENT_TYPE], "application/x-ndjson");
+
+        let text = String::from_utf8(res.body).unwrap();
+        let lines: Vec<serde_json::Value> = text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let [users @ .., end, signature] = lines.as_slice() else {
+            panic!("too few lines: {}", text);
+        };
+        assert_eq!(uuids(&serde_json::Value::from(users.to_vec())), expected);
+        assert_eq!(end["end"]["count"], 3);
+        assert!(end["end"]["cursor"].is_string());
+
+        // Signed over every byte before the signature line
+        let signed = &text[..text.rfind("{\"signature\"").unwrap()];
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"signing-key").unwrap();
+        mac.update(signed.as_bytes());
+        let expected_signature = hex::encode(mac.finalize().into_bytes());
+        assert_eq!(signature["signature"], expected_signature);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn delta_reports_added_and_removed_since_the_cursor(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        let (alice, bob) = (test_util::user(&pool, 1001).await, test_util::user(&pool, 1002).await);
+        let leaving = test_util::subscription(&pool, alice, server, 1, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await;
+
+        let full = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await.json();
+        let cursor = full["cursor"].as_str().unwrap().to_string();
+
+        let joining = test_util::subscription(&pool, bob, server, 2, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await;
+        sqlx::query("UPDATE subscriptions SET status = 'cancelled' WHERE xray_uuid = $1")
+            .bind(leaving)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let uri = format!("/api/internal/sync/delta?since={}", urlencode(&cursor));
+        let res = call(&state, get(&uri, &[("x-server-secret", SECRET)])).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let body = res.json();
+        assert_eq!(uuids(&body["added"]), vec![joining.to_string()]);
+        assert_eq!(body["removed"], serde_json::json!([leaving.to_string()]));
+        assert!(body["cursor"].as_str().unwrap() > cursor.as_str());
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn delta_answers_gone_for_an_unusable_cursor(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        test_util::server(&pool, "de-1", SECRET).await;
+
+        let stale = Utc::now() - Duration::seconds(DELTA_MAX_CURSOR_AGE_SECS + 60);
+        let future = Utc::now() + Duration::hours(1);
+        for since in [urlencode(&stale.to_rfc3339()), urlencode(&future.to_rfc3339()), "garbage".to_string()] {
+            let uri = format!("/api/internal/sync/delta?since={}", since);
+            let res = call(&state, get(&uri, &[("x-server-secret", SECRET)])).await;
+            assert_eq!(res.status, StatusCode::GONE, "since={}", since);
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn usage_reports_accumulate_per_subscription(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        let other = test_util::server(&pool, "de-2", "other-secret").await;
+        let (alice, bob) = (test_util::user(&pool, 1001).await, test_util::user(&pool, 1002).await);
+        let mine = test_util::subscription(&pool, alice, server, 1, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await;
+        let theirs = test_util::subscription(&pool, bob, other, 1, Utc::now() + Duration::days(1), SubscriptionKind::Paid).await;
+
+        for _ in 0..2 {
+            let report = serde_json::json!({ "users": [
+                { "email": mine.to_string(), "uplink": 100, "downlink": 1000 },
+                // Belongs to another server, so this agent can't bill it
+                { "email": theirs.to_string(), "uplink": 5, "downlink": 5 },
+            ]});
+            let res = call(&state, post_json("/api/internal/usage", &[("x-server-secret", SECRET)], report)).await;
+            assert_eq!(res.status, StatusCode::NO_CONTENT);
+        }
+
+        assert_eq!(test_util::bytes_used(&pool, mine).await, Some(2200));
+        assert_eq!(test_util::bytes_used(&pool, theirs).await, None);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn stats_count_subscriptions_in_their_grace_period(pool: PgPool) {
+        let state = AppState {
+            grace_minutes: 60,
+            ..test_util::state(pool.clone())
+        };
+        let server = test_util::server(&pool, "de-1", SECRET).await;
+        let now = Utc::now();
+        let mut synced = Vec::new();
+        for (tg_id, expire_date) in [
+            (1001, now + Duration::days(2)),
+            (1002, now - Duration::minutes(30)),
+            (1003, now - Duration::hours(2)),
+        ] {
+            let user = test_util::user(&pool, tg_id).await;
+            let uuid = test_util::subscription(&pool, user, server, 1, expire_date, SubscriptionKind::Paid).await;
+            if tg_id != 1003 {
+                synced.push(uuid.to_string());
+            }
+        }
+        synced.sort();
+
+        let res = call(&state, get("/api/internal/sync", &[("x-server-secret", SECRET)])).await;
+        assert_eq!(uuids(&res.json()["users"]), synced);
+
+        let res = call(&state, get("/api/internal/stats", &[("x-admin-token", test_util::ADMIN_TOKEN)])).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let stats = res.json();
+        assert_eq!(stats["active_users"], 2);
+        assert_eq!(stats["active_subscriptions"], 2);
+        // Only the one in its grace period leaves the synced set within a day
+        assert_eq!(stats["expiring_within_24h"], 1);
+        assert_eq!(stats["total_users"], 3);
+    }
+
+    // RFC 3339 cursors carry a '+' that a query string would read as a space
+    fn urlencode(value: &str) -> String {
+        url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+    }
+}
@@ -0,0 +1,214 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::error::ApiError;
+use crate::{db_error, lookup_error, require_admin, AppState};
+
+// Same bound extend_subscription applies to a single purchase
+const MAX_PLAN_DAYS: i32 = 3650;
+
+// What the agent can provision; matched against the protocols agents declare on sync
+const PLAN_PROTOCOLS: [&str; 4] = ["vless", "vmess", "trojan", "shadowsocks"];
+
+// Plans are rows of the tariffs table; plan_id elsewhere in the API is tariffs.id
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Plan {
+    id: i16,
+    name: String,
+    price: f64,
+    duration_days: i32,
+    speed_limit_mbps: i32,
+    xray_level: i32,
+    // NULL = unlimited traffic
+    byte_limit: Option<i64>,
+    // Named Xray instance on the agent; NULL = its default instance
+    xray_target: Option<String>,
+    // Protocols the plan's users may be provisioned on; NULL = any
+    protocols: Option<Vec<String>>,
+    // Agent inbound tags the plan's users are provisioned on; NULL = all of them
+    inbound_tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct PlanFields {
+    name: String,
+    price: f64,
+    duration_days: i32,
+    speed_limit_mbps: i32,
+    xray_level: i32,
+    #[serde(default)]
+    byte_limit: Option<i64>,
+    #[serde(default)]
+    xray_target: Option<String>,
+    #[serde(default)]
+    protocols: Option<Vec<String>>,
+    #[serde(default)]
+    inbound_tags: Option<Vec<String>>,
+}
+
+impl PlanFields {
+    // Lowercased and deduplicated; an empty list means any protocol, same as leaving it out
+    fn protocols(&self) -> Option<Vec<String>> {
+        let mut protocols: Vec<String> = self
+            .protocols
+            .iter()
+            .flatten()
+            .map(|p| p.trim().to_ascii_lowercase())
+            .collect();
+        protocols.sort();
+        protocols.dedup();
+        (!protocols.is_empty()).then_some(protocols)
+    }
+
+    // Trimmed and deduplicated; unlike protocols an empty list stays empty, which takes the
+    // plan's users off every inbound
+    fn inbound_tags(&self) -> Option<Vec<String>> {
+        self.inbound_tags.as_ref().map(|tags| {
+            let mut tags: Vec<String> = tags.iter().map(|t| t.trim().to_string()).collect();
+            tags.sort();
+            tags.dedup();
+            tags
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreatePlanRequest {
+    id: i16,
+    #[serde(flatten)]
+    fields: PlanFields,
+}
+
+fn validate(fields: &PlanFields) -> Result<(), ApiError> {
+    if fields.name.trim().is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "name must not be empty"));
+    }
+    if !fields.price.is_finite() || fields.price < 0.0 {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "price must be non-negative"));
+    }
+    if fields.duration_days <= 0 || fields.duration_days > MAX_PLAN_DAYS {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "duration_days out of range"));
+    }
+    if fields.speed_limit_mbps < 0 || fields.xray_level < 0 {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "speed_limit_mbps and xray_level must be non-negative"));
+    }
+    if fields.byte_limit.is_some_and(|b| b < 0) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "byte_limit must be non-negative"));
+    }
+    if !fields.protocols().iter().flatten().all(|p| PLAN_PROTOCOLS.contains(&p.as_str())) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "protocols must be among vless, vmess, trojan, shadowsocks",
+        ));
+    }
+    if fields.inbound_tags().iter().flatten().any(|t| t.is_empty()) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "inbound_tags must not contain empty tags"));
+    }
+    Ok(())
+}
+
+// price is NUMERIC(10, 2); it travels as a float and is rounded to cents on the way in
+const PLAN_COLUMNS: &str = "id, name, price::float8 AS price, duration_days, speed_limit_mbps, xray_level, byte_limit, xray_target, protocols, \
+                            inbound_tags";
+
+pub async fn list_plans(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, ApiError> {
+    let plans: Vec<Plan> = sqlx::query_as(&format!("SELECT {} FROM tariffs ORDER BY id", PLAN_COLUMNS))
+        .fetch_all(&state.pool)
+        .await
+        .map_err(db_error("list plans"))?;
+    Ok(Json(plans))
+}
+
+pub async fn create_plan(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<CreatePlanRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+    if req.id <= 0 {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "id must be positive"));
+    }
+    validate(&req.fields)?;
+
+    let f = &req.fields;
+    let plan: Plan = sqlx::query_as(&format!(
+        r#"
+        INSERT INTO tariffs (id, name, price, duration_days, speed_limit_mbps, xray_level, byte_limit, xray_target, protocols,
+                             inbound_tags)
+        VALUES ($1, $2, ROUND($3::numeric, 2), $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (id) DO NOTHING
+        RETURNING {}
+        "#,
+        PLAN_COLUMNS
+    ))
+    .bind(req.id)
+    .bind(f.name.trim())
+    .bind(f.price)
+    .bind(f.duration_days)
+    .bind(f.speed_limit_mbps)
+    .bind(f.xray_level)
+    .bind(f.byte_limit)
+    .bind(f.xray_target.as_deref().map(str::trim).filter(|t| !t.is_empty()))
+    .bind(f.protocols())
+    .bind(f.inbound_tags())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error("create plan"))?
+    .ok_or(ApiError::new(StatusCode::CONFLICT, "plan id already exists"))?;
+
+    info!("Created plan {} ({})", plan.id, plan.name);
+    Ok((StatusCode::CREATED, Json(plan)))
+}
+
+// Replaces every field of an existing plan. A new byte_limit applies to existing subscriptions
+// as their usage is reported; a new xray_level only reaches users provisioned afterwards.
+pub async fn update_plan(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<i16>,
+    Json(fields): Json<PlanFields>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+    validate(&fields)?;
+
+    let plan: Plan = sqlx::query_as(&format!(
+        r#"
+        UPDATE tariffs
+        SET name = $2,
+            price = ROUND($3::numeric, 2),
+            duration_days = $4,
+            speed_limit_mbps = $5,
+            xray_level = $6,
+            byte_limit = $7,
+            xray_target = $8,
+            protocols = $9,
+            inbound_tags = $10
+        WHERE id = $1
+        RETURNING {}
+        "#,
+        PLAN_COLUMNS
+    ))
+    .bind(id)
+    .bind(fields.name.trim())
+    .bind(fields.price)
+    .bind(fields.duration_days)
+    .bind(fields.speed_limit_mbps)
+    .bind(fields.xray_level)
+    .bind(fields.byte_limit)
+    .bind(fields.xray_target.as_deref().map(str::trim).filter(|t| !t.is_empty()))
+    .bind(fields.protocols())
+    .bind(fields.inbound_tags())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(lookup_error("update plan", "plan not found"))?;
+
+    info!("Updated plan {} ({})", plan.id, plan.name);
+    Ok(Json(plan))
+}
@@ -0,0 +1,229 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::subscriptions::{renew, SubscriptionKind};
+use crate::user_webhook::UserCreated;
+use crate::users::{grant_subscription, idempotency_key, validate_tg_id};
+use crate::{db_error, links, require_admin, AppState};
+
+#[derive(Deserialize)]
+pub struct ProvisionRequest {
+    tg_id: i64,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    full_name: Option<String>,
+    // Starts or renews the subscription on this plan for its duration_days. Without it a new
+    // user gets the trial and a returning one keeps what they have.
+    #[serde(default)]
+    plan_id: Option<i16>,
+    // Where a new subscription goes; the least loaded server when omitted. An existing
+    // subscription stays on its server, so naming a different one is a conflict.
+    #[serde(default)]
+    server_id: Option<Uuid>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ProvisionedPlan {
+    id: i16,
+    name: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ProvisionResponse {
+    user_id: Uuid,
+    tg_id: i64,
+    // false when the tg_id was already registered
+    created: bool,
+    uuid: Uuid,
+    server_id: Uuid,
+    plan: ProvisionedPlan,
+    kind: SubscriptionKind,
+    expire_date: DateTime<Utc>,
+    // plan_id renewed an existing subscription
+    renewed: bool,
+    // The renewed subscription had lapsed, so its traffic quota starts over
+    usage_reset: bool,
+    // Ready-to-import vless:// link for the subscription's server
+    link: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct Provisioned {
+    xray_uuid: Uuid,
+    server_id: Uuid,
+    tariff_id: i16,
+    tariff_name: String,
+    kind: SubscriptionKind,
+    expire_date: DateTime<Utc>,
+}
+
+// Everything the bot does for a /start or a purchase in one call and one transaction: register
+// the user, grant the trial or renew on plan_id, and return the link to hand out. Nothing is
+// left half done, e.g. a renewal without a link to show for it.
+// A repeated Idempotency-Key gets the first call's response back, so a retried purchase isn't
+// renewed twice.
+pub async fn provision(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<ProvisionRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+    validate_tg_id(req.tg_id)?;
+
+    let idempotency_key = idempotency_key(&headers)?;
+    if let Some(key) = &idempotency_key {
+        if let Some((tg_id, reply)) = state.provision_replies.get(key) {
+            if tg_id != req.tg_id {
+                return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "Idempotency-Key was used for another tg_id"));
+            }
+            return Ok(Json(reply));
+        }
+    }
+
+    let mut tx = state.pool.begin().await.map_err(db_error("provision"))?;
+
+    let (user_id, is_active, created) = sqlx::query_as::<_, (Uuid, bool, bool)>(
+        r#"
+        INSERT INTO users (tg_id, username, full_name)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (tg_id) DO UPDATE SET
+            username = COALESCE(EXCLUDED.username, users.username),
+            full_name = COALESCE(EXCLUDED.full_name, users.full_name)
+        RETURNING id, is_active, (xmax = 0)
+        "#,
+    )
+    .bind(req.tg_id)
+    .bind(&req.username)
+    .bind(&req.full_name)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error("provision"))?;
+    if !is_active {
+        return Err(ApiError::new(StatusCode::CONFLICT, "user is suspended"));
+    }
+
+    let plan_days: Option<i32> = match req.plan_id {
+        Some(plan_id) => Some(
+            sqlx::query_scalar("SELECT duration_days FROM tariffs WHERE id = $1")
+                .bind(plan_id)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(db_error("provision"))?
+                .ok_or(ApiError::new(StatusCode::BAD_REQUEST, "unknown plan_id"))?,
+        ),
+        None => None,
+    };
+
+    // Locked so a concurrent provision or extend for the same user waits for us
+    let latest: Option<(Uuid, Uuid)> = sqlx::query_as(
+        r#"
+        SELECT xray_uuid, server_id FROM subscriptions
+        WHERE user_id = $1
+        ORDER BY expire_date DESC
+        LIMIT 1
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error("provision"))?;
+
+    let (mut renewed, mut usage_reset) = (false, false);
+    let xray_uuid = match latest {
+        Some((_, server_id)) if req.server_id.is_some_and(|s| s != server_id) => {
+            return Err(ApiError::new(StatusCode::CONFLICT, "user's subscription is on another server"));
+        }
+        Some((xray_uuid, _)) => match (req.plan_id, plan_days) {
+            (Some(plan_id), Some(days)) => {
+                let (xray_uuid, _, lapsed) = renew(&mut tx, req.tg_id, days, plan_id).await?;
+                (renewed, usage_reset) = (true, lapsed);
+                xray_uuid
+            }
+            _ => xray_uuid,
+        },
+        None => {
+            let (tariff_id, minutes, kind) = match (req.plan_id, plan_days) {
+                (Some(plan_id), Some(days)) => (plan_id, days * 24 * 60, SubscriptionKind::Paid),
+                _ if state.trial_minutes > 0 => (state.trial_tariff_id, state.trial_minutes, SubscriptionKind::Trial),
+                _ => {
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "plan_id is required while free trials are off",
+                    ))
+                }
+            };
+            grant_subscription(&mut tx, user_id, tariff_id, minutes, kind, req.server_id)
+                .await?
+                .ok_or(match req.server_id {
+                    Some(_) => ApiError::new(StatusCode::CONFLICT, "server is disabled, full or reserved for the other kind"),
+                    None => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "no server with free slots"),
+                })?
+        }
+    };
+
+    let sub: Provisioned = sqlx::query_as(
+        r#"
+        SELECT s.xray_uuid, s.server_id, s.tariff_id, t.name AS tariff_name, s.kind, s.expire_date
+        FROM subscriptions s
+        JOIN tariffs t ON t.id = s.tariff_id
+        WHERE s.xray_uuid = $1
+        "#,
+    )
+    .bind(xray_uuid)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error("provision"))?;
+    let link = links::link_for(&mut *tx, xray_uuid).await?;
+
+    tx.commit().await.map_err(db_error("provision"))?;
+
+    if let (true, Some(hook)) = (created, &state.user_created_webhook) {
+        hook.notify(UserCreated {
+            id: user_id,
+            tg_id: req.tg_id,
+            uuid: Some(xray_uuid),
+        });
+    }
+    info!(
+        "Provisioned tg_id {} ({}): {} on tariff {} until {}{}",
+        req.tg_id,
+        if created { "new" } else { "returning" },
+        sub.xray_uuid,
+        sub.tariff_id,
+        sub.expire_date,
+        if renewed { ", renewed" } else { "" }
+    );
+
+    let response = ProvisionResponse {
+        user_id,
+        tg_id: req.tg_id,
+        created,
+        uuid: sub.xray_uuid,
+        server_id: sub.server_id,
+        plan: ProvisionedPlan {
+            id: sub.tariff_id,
+            name: sub.tariff_name,
+        },
+        kind: sub.kind,
+        expire_date: sub.expire_date,
+        renewed,
+        usage_reset,
+        link,
+    };
+    if let Some(key) = idempotency_key {
+        state.provision_replies.insert(key, (req.tg_id, response.clone()));
+    }
+    Ok(Json(response))
+}
@@ -0,0 +1,116 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+use crate::AppState;
+
+// Past this many tracked IPs, fully refilled buckets are dropped on the next check
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Token bucket per client IP: `per_minute` requests per minute sustained, bursts up to the same amount
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes one token for `ip`. On refusal, returns how long until the next token is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let capacity = self.per_minute as f64;
+        let refill_per_sec = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.last_refill).as_secs_f64() * refill_per_sec < capacity
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill_per_sec))
+        }
+    }
+}
+
+// Middleware for routes that should be limited per client IP; a no-op when no limiter is configured
+pub async fn limit_by_ip(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(limiter) = &state.create_user_limiter {
+        if let Err(wait) = limiter.check(addr.ip()) {
+            tracing::warn!("Rate limited {} on {}", addr.ip(), request.uri().path());
+            return ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded")
+                .with_retry_after(wait)
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, call, post_json, ADMIN_TOKEN};
+
+    #[test]
+    fn refusal_says_when_the_next_token_comes() {
+        let limiter = RateLimiter::new(2);
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        assert!(limiter.check(ip).is_ok());
+        assert!(limiter.check(ip).is_ok());
+        let wait = limiter.check(ip).expect_err("bucket is empty");
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30), "{:?}", wait);
+        // Other clients have their own bucket
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 2])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn limited_requests_get_retry_after() {
+        let state = AppState {
+            create_user_limiter: Some(Arc::new(RateLimiter::new(1))),
+            ..test_util::state(test_util::unreachable_pool())
+        };
+        // tg_id 0 is turned away before the database, which isn't there
+        let request = || post_json("/api/v1/users", &[("x-admin-token", ADMIN_TOKEN)], serde_json::json!({ "tg_id": 0 }));
+
+        assert_eq!(call(&state, request()).await.status, StatusCode::BAD_REQUEST);
+        let limited = call(&state, request()).await;
+        assert_eq!(limited.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers[axum::http::header::RETRY_AFTER], "60");
+    }
+}
@@ -0,0 +1,117 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::{lookup_error, require_admin, AppState};
+
+// Reality shortIds are hex strings of up to 8 bytes; Xray allows a few per server
+const MAX_SHORT_ID_LEN: usize = 16;
+const MAX_SHORT_IDS: usize = 16;
+
+// What a client needs to connect to a server, for the bot to build links from. The API secret
+// and capacity bookkeeping stay out of it.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct ServerInfo {
+    id: Uuid,
+    slug: String,
+    host: String,
+    domain: String,
+    is_enabled: bool,
+    vless_port: i32,
+    // NULL = use domain
+    sni: Option<String>,
+    fingerprint: String,
+    flow: String,
+    transport: String,
+    // Reality: x25519 public key as printed by `xray x25519`, and the accepted shortIds
+    public_key: String,
+    short_ids: Vec<String>,
+}
+
+const SERVER_COLUMNS: &str = "id, slug, host(ip_address) AS host, domain, is_enabled, vless_port, sni, \
+                              fingerprint, flow, transport, public_key, short_ids";
+
+#[derive(Deserialize)]
+pub struct RealityParams {
+    public_key: String,
+    #[serde(default)]
+    short_ids: Vec<String>,
+}
+
+// Xray prints the key base64url without padding; any 32 bytes are a usable curve25519 public key.
+// The standard alphabet and padding are accepted too and stored in Xray's form.
+fn normalize_public_key(key: &str) -> Option<String> {
+    use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+    let key = key.trim();
+    let bytes = URL_SAFE_NO_PAD
+        .decode(key)
+        .or_else(|_| STANDARD.decode(key))
+        .ok()
+        .filter(|b| b.len() == 32)?;
+    Some(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+fn valid_short_id(id: &str) -> bool {
+    id.len() <= MAX_SHORT_ID_LEN && id.len().is_multiple_of(2) && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub async fn get_server(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let server: ServerInfo = sqlx::query_as(&format!("SELECT {} FROM servers WHERE id = $1", SERVER_COLUMNS))
+        .bind(id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(lookup_error("get server", "server not found"))?;
+    Ok(Json(server))
+}
+
+// Replaces the server's Reality parameters, e.g. after regenerating its key pair. Links handed
+// out before keep the old key, so clients need fresh ones.
+pub async fn set_reality(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    Json(params): Json<RealityParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let public_key = normalize_public_key(&params.public_key).ok_or(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "public_key must be a base64 x25519 public key (32 bytes)",
+    ))?;
+    let short_ids: Vec<String> = params.short_ids.iter().map(|s| s.trim().to_ascii_lowercase()).collect();
+    if short_ids.len() > MAX_SHORT_IDS || !short_ids.iter().all(|s| valid_short_id(s)) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "short_ids must be hex strings of even length, at most 16 characters",
+        ));
+    }
+
+    let server: ServerInfo = sqlx::query_as(&format!(
+        "UPDATE servers SET public_key = $2, short_ids = $3 WHERE id = $1 RETURNING {}",
+        SERVER_COLUMNS
+    ))
+    .bind(id)
+    .bind(&public_key)
+    .bind(&short_ids)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(lookup_error("set reality", "server not found"))?;
+
+    info!("Updated Reality parameters of server {} ({})", server.id, server.slug);
+    Ok(Json(server))
+}
@@ -0,0 +1,260 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::{db_error, lookup_error, require_admin, AppState};
+
+// Mirrors the sub_status enum in Postgres
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "sub_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionStatus {
+    Active,
+    Expired,
+    Banned,
+    Cancelled,
+}
+
+// Mirrors the sub_kind enum in Postgres
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "sub_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionKind {
+    Trial,
+    Paid,
+}
+
+// Upper bound on a single extension, mostly to catch unit mix-ups (e.g. seconds sent as days)
+const MAX_EXTEND_DAYS: i32 = 3650;
+
+#[derive(Deserialize)]
+pub struct ExtendRequest {
+    tg_id: i64,
+    duration_days: i32,
+    // tariffs.id to switch the subscription to
+    plan_id: i16,
+}
+
+#[derive(Serialize)]
+pub struct ExtendResponse {
+    uuid: Uuid,
+    plan_id: i16,
+    expire_date: DateTime<Utc>,
+    // The subscription had lapsed, so this started a new period with a fresh traffic quota
+    usage_reset: bool,
+}
+
+// Paid renewal of the user's latest subscription. The new period starts at the current
+// expiry if that's still ahead, or at now() for a lapsed subscription. Only the latter resets
+// the traffic counted against byte_limit: an early renewal extends the running period, whose
+// usage keeps counting.
+pub async fn extend_subscription(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<ExtendRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    if req.duration_days <= 0 || req.duration_days > MAX_EXTEND_DAYS {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "duration_days out of range"));
+    }
+
+    let tariff_exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM tariffs WHERE id = $1)")
+        .bind(req.plan_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error("extend subscription"))?;
+    if !tariff_exists {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "unknown plan_id"));
+    }
+
+    let mut tx = state.pool.begin().await.map_err(db_error("extend subscription"))?;
+
+    let (uuid, expire_date, lapsed) = renew(&mut tx, req.tg_id, req.duration_days, req.plan_id).await?;
+    tx.commit().await.map_err(db_error("extend subscription"))?;
+
+    info!(
+        "Extended subscription {} of tg_id {} by {} days until {}{}",
+        uuid,
+        req.tg_id,
+        req.duration_days,
+        expire_date,
+        if lapsed { ", usage reset for the new period" } else { "" }
+    );
+    Ok(Json(ExtendResponse {
+        uuid,
+        plan_id: req.plan_id,
+        expire_date,
+        usage_reset: lapsed,
+    }))
+}
+
+// Renews the latest subscription of `tg_id` by `duration_days` on `plan_id`, as a paid one.
+// Returns its Xray UUID, the new expiry and whether it had lapsed (its usage was then reset).
+pub async fn renew(
+    conn: &mut PgConnection,
+    tg_id: i64,
+    duration_days: i32,
+    plan_id: i16,
+) -> Result<(Uuid, DateTime<Utc>, bool), ApiError> {
+    let (uuid, expire_date, lapsed) = sqlx::query_as::<_, (Uuid, DateTime<Utc>, bool)>(
+        r#"
+        UPDATE subscriptions sub
+        SET expire_date = GREATEST(now(), sub.expire_date) + make_interval(days => $2),
+            tariff_id = $3,
+            status = $4,
+            -- An extended trial has been paid for from here on
+            kind = $5,
+            notified_at = NULL
+        FROM (
+            SELECT s.id, s.expire_date <= now() AS lapsed
+            FROM subscriptions s
+            JOIN users u ON u.id = s.user_id
+            WHERE u.tg_id = $1
+            ORDER BY s.expire_date DESC
+            LIMIT 1
+            FOR UPDATE OF s
+        ) prev
+        WHERE sub.id = prev.id
+        RETURNING sub.xray_uuid, sub.expire_date, prev.lapsed
+        "#,
+    )
+    .bind(tg_id)
+    .bind(duration_days)
+    .bind(plan_id)
+    .bind(SubscriptionStatus::Active)
+    .bind(SubscriptionKind::Paid)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(lookup_error("extend subscription", "subscription not found"))?;
+
+    if lapsed {
+        sqlx::query("UPDATE usage SET bytes_up = 0, bytes_down = 0, updated_at = now() WHERE xray_uuid = $1")
+            .bind(uuid)
+            .execute(&mut *conn)
+            .await
+            .map_err(db_error("extend subscription"))?;
+    }
+
+    Ok((uuid, expire_date, lapsed))
+}
+
+// Repair button for a user whose Xray entry no longer matches the DB (e.g. edited by hand).
+// Nothing about the subscription changes; its agent is told to remove and re-add the user.
+pub async fn request_resync(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(xray_uuid): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let mut tx = state.pool.begin().await.map_err(db_error("resync"))?;
+
+    sqlx::query("DELETE FROM resync_requests WHERE requested_at < now() - make_interval(secs => $1)")
+        .bind(crate::RESYNC_HINT_WINDOW_SECS as f64)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error("resync"))?;
+
+    sqlx::query_scalar::<_, Uuid>(
+        r#"
+        INSERT INTO resync_requests (xray_uuid)
+        SELECT xray_uuid FROM subscriptions WHERE xray_uuid = $1
+        ON CONFLICT (xray_uuid) DO UPDATE SET requested_at = now()
+        RETURNING xray_uuid
+        "#,
+    )
+    .bind(xray_uuid)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(lookup_error("resync", "subscription not found"))?;
+
+    // Puts an active subscription back into the next delta's `added`, so the agent re-adds it
+    sqlx::query("UPDATE subscriptions SET updated_at = now() WHERE xray_uuid = $1")
+        .bind(xray_uuid)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error("resync"))?;
+
+    tx.commit().await.map_err(db_error("resync"))?;
+
+    info!("Resync requested for {}", xray_uuid);
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, call, post_json, ADMIN_TOKEN};
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn extend_renews_the_latest_subscription_as_paid(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", "agent-secret").await;
+        let user = test_util::user(&pool, 42).await;
+        let expires = Utc::now() + chrono::Duration::days(2);
+        let uuid = test_util::subscription(&pool, user, server, 1, expires, SubscriptionKind::Trial).await;
+
+        let body = serde_json::json!({ "tg_id": 42, "duration_days": 30, "plan_id": 3 });
+        let res = call(&state, post_json("/api/v1/subscriptions/extend", &[("x-admin-token", ADMIN_TOKEN)], body)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = res.json();
+        assert_eq!(res["uuid"], uuid.to_string());
+        assert_eq!(res["plan_id"], 3);
+
+        // Extended from the old expiry, not from now
+        let expire_date: DateTime<Utc> = res["expire_date"].as_str().unwrap().parse().unwrap();
+        assert!((expire_date - (expires + chrono::Duration::days(30))).num_seconds().abs() < 1);
+
+        let (tariff_id, kind): (i16, SubscriptionKind) =
+            sqlx::query_as("SELECT tariff_id, kind FROM subscriptions WHERE xray_uuid = $1")
+                .bind(uuid)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((tariff_id, kind), (3, SubscriptionKind::Paid));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn extend_resets_usage_only_for_a_lapsed_subscription(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        let server = test_util::server(&pool, "de-1", "agent-secret").await;
+        let now = Utc::now();
+        let cases = [(42, now - chrono::Duration::days(1), true), (43, now + chrono::Duration::days(1), false)];
+        for (tg_id, expires, lapsed) in cases {
+            let user = test_util::user(&pool, tg_id).await;
+            let uuid = test_util::subscription(&pool, user, server, 2, expires, SubscriptionKind::Paid).await;
+            test_util::usage(&pool, uuid, 5000).await;
+
+            let body = serde_json::json!({ "tg_id": tg_id, "duration_days": 30, "plan_id": 2 });
+            let res = call(&state, post_json("/api/v1/subscriptions/extend", &[("x-admin-token", ADMIN_TOKEN)], body)).await.json();
+            assert_eq!(res["usage_reset"], lapsed, "tg_id {}", tg_id);
+            let expected = if lapsed { 0 } else { 5000 };
+            assert_eq!(test_util::bytes_used(&pool, uuid).await, Some(expected), "tg_id {}", tg_id);
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn extend_without_a_subscription_is_not_found(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        test_util::user(&pool, 42).await;
+
+        let body = serde_json::json!({ "tg_id": 42, "duration_days": 30, "plan_id": 1 });
+        let res = call(&state, post_json("/api/v1/subscriptions/extend", &[("x-admin-token", ADMIN_TOKEN)], body)).await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,170 @@
+// Shared setup for the handler tests: an AppState over a #[sqlx::test] pool, a way to send
+// requests through the full router, and fixture rows. The pool comes from a throwaway database
+// with ./migrations applied, so tests that use it need DATABASE_URL and are #[ignore]d by default:
+//   DATABASE_URL=postgres://... cargo test -p control_plane -- --include-ignored
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderMap, Method, Request, StatusCode},
+};
+use chrono::{DateTime, Utc};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::subscriptions::SubscriptionKind;
+use crate::{idempotency, router, AppState};
+
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+// Trials are granted on the seeded "Basic" tariff
+pub const TRIAL_TARIFF_ID: i16 = 1;
+
+pub fn state(pool: PgPool) -> AppState {
+    AppState {
+        pool,
+        metrics: PrometheusBuilder::new().build_recorder().handle(),
+        admin_token: Some(ADMIN_TOKEN.to_string()),
+        trial_minutes: 60,
+        trial_tariff_id: TRIAL_TARIFF_ID,
+        create_user_limiter: None,
+        create_user_replies: Arc::new(idempotency::IdempotencyCache::new(Duration::from_secs(3600))),
+        provision_replies: Arc::new(idempotency::IdempotencyCache::new(Duration::from_secs(3600))),
+        sync_signing_key: None,
+        user_created_webhook: None,
+        grace_minutes: 0,
+    }
+}
+
+// For requests that must be answered before any query: nothing listens there, so one that does
+// reach the database fails with a 500
+pub fn unreachable_pool() -> PgPool {
+    sqlx::postgres::PgPoolOptions::new()
+        .acquire_timeout(Duration::from_millis(200))
+        .connect_lazy("postgres://postgres@127.0.0.1:1/none")
+        .expect("valid database URL")
+}
+
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("response body is JSON")
+    }
+}
+
+// Runs `req` through the router as if it came from 127.0.0.1
+pub async fn call(state: &AppState, mut req: Request<Body>) -> Reply {
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+    let res = router(Arc::new(state.clone()), None)
+        .oneshot(req)
+        .await
+        .expect("router is infallible");
+    let status = res.status();
+    let headers = res.headers().clone();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .expect("response body reads")
+        .to_vec();
+    Reply { status, headers, body }
+}
+
+pub fn get(uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+    request(Method::GET, uri, headers, Body::empty())
+}
+
+pub fn post_json(uri: &str, headers: &[(&str, &str)], body: serde_json::Value) -> Request<Body> {
+    let mut headers = headers.to_vec();
+    headers.push(("content-type", "application/json"));
+    request(Method::POST, uri, &headers, Body::from(body.to_string()))
+}
+
+fn request(method: Method, uri: &str, headers: &[(&str, &str)], body: Body) -> Request<Body> {
+    let mut req = Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    req.body(body).expect("valid request")
+}
+
+pub async fn server(pool: &PgPool, slug: &str, api_secret: &str) -> Uuid {
+    sqlx::query_scalar(
+        "INSERT INTO servers (slug, ip_address, domain, public_key, api_secret)
+         VALUES ($1, '10.0.0.1', $1 || '.example.com', 'pk', $2) RETURNING id",
+    )
+    .bind(slug)
+    .bind(api_secret)
+    .fetch_one(pool)
+    .await
+    .expect("insert server")
+}
+
+pub async fn user(pool: &PgPool, tg_id: i64) -> Uuid {
+    sqlx::query_scalar("INSERT INTO users (tg_id) VALUES ($1) RETURNING id")
+        .bind(tg_id)
+        .fetch_one(pool)
+        .await
+        .expect("insert user")
+}
+
+// An active subscription; its email is the Xray UUID, so tests can match either
+pub async fn subscription(
+    pool: &PgPool,
+    user_id: Uuid,
+    server_id: Uuid,
+    tariff_id: i16,
+    expire_date: DateTime<Utc>,
+    kind: SubscriptionKind,
+) -> Uuid {
+    sqlx::query_scalar(
+        "WITH fresh AS (SELECT gen_random_uuid() AS xray_uuid)
+         INSERT INTO subscriptions (user_id, server_id, tariff_id, xray_uuid, email, expire_date, kind)
+         SELECT $1, $2, $3, xray_uuid, xray_uuid::text, $4, $5 FROM fresh
+         RETURNING xray_uuid",
+    )
+    .bind(user_id)
+    .bind(server_id)
+    .bind(tariff_id)
+    .bind(expire_date)
+    .bind(kind)
+    .fetch_one(pool)
+    .await
+    .expect("insert subscription")
+}
+
+pub async fn usage(pool: &PgPool, xray_uuid: Uuid, bytes: i64) {
+    sqlx::query("INSERT INTO usage (xray_uuid, bytes_down) VALUES ($1, $2)")
+        .bind(xray_uuid)
+        .bind(bytes)
+        .execute(pool)
+        .await
+        .expect("insert usage");
+}
+
+pub async fn bytes_used(pool: &PgPool, xray_uuid: Uuid) -> Option<i64> {
+    sqlx::query_scalar("SELECT bytes_used FROM usage WHERE xray_uuid = $1")
+        .bind(xray_uuid)
+        .fetch_optional(pool)
+        .await
+        .expect("read usage")
+}
+
+// UUIDs of a full sync (or delta `added`) body, sorted
+pub fn uuids(users: &serde_json::Value) -> Vec<String> {
+    let mut uuids: Vec<String> = users
+        .as_array()
+        .expect("user list")
+        .iter()
+        .map(|u| u["uuid"].as_str().expect("uuid").to_string())
+        .collect();
+    uuids.sort();
+    uuids
+}
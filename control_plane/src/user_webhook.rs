@@ -0,0 +1,63 @@
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Waits before the 2nd and 3rd attempt; the event is dropped (and logged) after that
+const RETRY_DELAYS_SECS: [u64; 2] = [1, 5];
+
+#[derive(Serialize, Clone)]
+pub struct UserCreated {
+    pub id: Uuid,
+    pub tg_id: i64,
+    // Trial subscription's Xray UUID; null when no trial was granted
+    pub uuid: Option<Uuid>,
+}
+
+// USER_CREATED_WEBHOOK_URL: told about every genuinely new user, whichever endpoint created it
+#[derive(Clone)]
+pub struct UserCreatedWebhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl UserCreatedWebhook {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("reqwest client");
+        Self { client, url }
+    }
+
+    // Fire and forget, so a slow receiver never holds up the API response
+    pub fn notify(&self, event: UserCreated) {
+        let hook = self.clone();
+        tokio::spawn(async move { hook.deliver(&event).await });
+    }
+
+    async fn deliver(&self, event: &UserCreated) {
+        let mut delays = RETRY_DELAYS_SECS.iter();
+        loop {
+            let result = async {
+                self.client.post(&self.url).json(event).send().await?.error_for_status()?;
+                reqwest::Result::Ok(())
+            }
+            .await;
+            match (result, delays.next()) {
+                (Ok(()), _) => {
+                    metrics::counter!("user_created_webhook_total", "result" => "ok").increment(1);
+                    return;
+                }
+                (Err(e), Some(delay)) => {
+                    tracing::warn!("User created webhook for tg_id {} failed, retrying: {}", event.tg_id, e);
+                    tokio::time::sleep(Duration::from_secs(*delay)).await;
+                }
+                (Err(e), None) => {
+                    metrics::counter!("user_created_webhook_total", "result" => "error").increment(1);
+                    tracing::error!("User created webhook for tg_id {} failed, giving up: {}", event.tg_id, e);
+                    return;
+                }
+            }
+        }
+    }
+}
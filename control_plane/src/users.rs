@@ -0,0 +1,674 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::subscriptions::{SubscriptionKind, SubscriptionStatus};
+use crate::user_webhook::UserCreated;
+use crate::error::ApiError;
+use crate::{db_error, lookup_error, require_admin, AppState};
+
+// Longest Idempotency-Key we keep; clients normally send a UUID
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+// Telegram documents user ids as positive with at most 52 significant bits
+const MAX_TG_ID: i64 = (1 << 52) - 1;
+
+// Accepted values of users.source; single creates that leave it out come from the bot, bulk
+// entries from an import
+const USER_SOURCES: [&str; 3] = ["telegram", "admin", "import"];
+const DEFAULT_USER_SOURCE: &str = "telegram";
+const BULK_USER_SOURCE: &str = "import";
+
+fn validate_source(source: Option<&str>) -> Result<&str, ApiError> {
+    match source {
+        None => Ok(DEFAULT_USER_SOURCE),
+        Some(s) if USER_SOURCES.contains(&s) => Ok(s),
+        Some(_) => Err(ApiError::new(StatusCode::BAD_REQUEST, "source must be one of telegram, admin, import")),
+    }
+}
+
+pub fn validate_tg_id(tg_id: i64) -> Result<(), ApiError> {
+    if tg_id <= 0 {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "tg_id must be positive"));
+    }
+    if tg_id > MAX_TG_ID {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "tg_id outside Telegram's id range"));
+    }
+    Ok(())
+}
+
+// The request's Idempotency-Key, if it sent a usable one
+pub fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    match headers.get("idempotency-key") {
+        Some(value) => {
+            let key = value
+                .to_str()
+                .ok()
+                .filter(|k| !k.is_empty() && k.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+                .ok_or(ApiError::new(StatusCode::BAD_REQUEST, "invalid Idempotency-Key"))?;
+            Ok(Some(key.to_string()))
+        }
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateUserRequest {
+    tg_id: i64,
+    username: Option<String>,
+    full_name: Option<String>,
+    // tariffs.id to start a new user on for the plan's duration_days; the trial when omitted
+    plan_id: Option<i16>,
+    // Recorded for new users only; a returning user keeps the source they first came from
+    #[serde(default)]
+    source: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct CreateUserResponse {
+    id: Uuid,
+    tg_id: i64,
+    // Xray UUID of the user's latest subscription (the trial or plan for new users);
+    // null when the user has none, e.g. trials disabled
+    uuid: Option<Uuid>,
+    // false when the tg_id was already registered ("welcome back")
+    created: bool,
+}
+
+// Registers a Telegram user (idempotent on tg_id) and, the first time round, grants the
+// free trial, or plan_id's subscription when given, on the least loaded server.
+// FREE_TRIAL_MINUTES=0 turns trials off.
+// A repeated Idempotency-Key gets the first call's response back unchanged.
+pub async fn create_user(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<CreateUserRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+    validate_tg_id(req.tg_id)?;
+    let source = validate_source(req.source.as_deref())?;
+
+    let idempotency_key = idempotency_key(&headers)?;
+    if let Some(key) = &idempotency_key {
+        if let Some((tg_id, reply)) = state.create_user_replies.get(key) {
+            if tg_id != req.tg_id {
+                return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "Idempotency-Key was used for another tg_id"));
+            }
+            return Ok(Json(reply));
+        }
+    }
+
+    // One transaction, so a user is never left registered without the trial they were due
+    let mut tx = state.pool.begin().await.map_err(db_error("create user"))?;
+
+    // xmax is 0 only for a freshly inserted row version, not one rewritten by ON CONFLICT DO UPDATE
+    let user = sqlx::query!(
+        r#"
+        INSERT INTO users (tg_id, username, full_name, source)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (tg_id) DO UPDATE SET
+            username = COALESCE(EXCLUDED.username, users.username),
+            full_name = COALESCE(EXCLUDED.full_name, users.full_name)
+        RETURNING id, (xmax = 0) AS "created!"
+        "#,
+        req.tg_id,
+        req.username,
+        req.full_name,
+        source,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error("create user"))?;
+
+    let (user_id, created) = (user.id, user.created);
+
+    // (tariff, length in minutes, kind) of the first subscription
+    let grant = match req.plan_id {
+        Some(plan_id) => {
+            let duration_days: i32 = sqlx::query_scalar("SELECT duration_days FROM tariffs WHERE id = $1")
+                .bind(plan_id)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(db_error("create user"))?
+                .ok_or(ApiError::new(StatusCode::BAD_REQUEST, "unknown plan_id"))?;
+            Some((plan_id, duration_days * 24 * 60, SubscriptionKind::Paid))
+        }
+        None if state.trial_minutes > 0 => Some((state.trial_tariff_id, state.trial_minutes, SubscriptionKind::Trial)),
+        None => None,
+    };
+    let granted_uuid = match grant {
+        Some((tariff_id, minutes, kind)) => grant_subscription(&mut tx, user_id, tariff_id, minutes, kind, None).await?,
+        None => None,
+    };
+
+    // Returning users get the subscription they already have
+    let uuid = match granted_uuid {
+        Some(uuid) => Some(uuid),
+        None => sqlx::query_scalar!(
+            "SELECT xray_uuid FROM subscriptions WHERE user_id = $1 ORDER BY expire_date DESC LIMIT 1",
+            user_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error("create user"))?,
+    };
+
+    tx.commit().await.map_err(db_error("create user"))?;
+
+    if let (true, Some(hook)) = (created, &state.user_created_webhook) {
+        hook.notify(UserCreated {
+            id: user_id,
+            tg_id: req.tg_id,
+            uuid,
+        });
+    }
+
+    let response = CreateUserResponse {
+        id: user_id,
+        tg_id: req.tg_id,
+        uuid,
+        created,
+    };
+    if let Some(key) = idempotency_key {
+        state.create_user_replies.insert(key, (req.tg_id, response.clone()));
+    }
+    Ok(Json(response))
+}
+
+const MAX_BULK_USERS: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct BulkCreateRequest {
+    users: Vec<CreateUserRequest>,
+}
+
+#[derive(Serialize)]
+pub struct BulkCreateResponse {
+    users: Vec<CreateUserResponse>,
+}
+
+// Imports many users in one transaction, e.g. when migrating from another system. Same upsert as
+// create_user, but no trials are granted: imported users bring their subscriptions with them.
+// A tg_id repeated in the batch is registered once, with the last entry's names.
+pub async fn create_users_bulk(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<BulkCreateRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+    if req.users.is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "users is empty"));
+    }
+    if req.users.len() > MAX_BULK_USERS {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "too many users in one batch (max 1000)"));
+    }
+
+    // ON CONFLICT can't touch the same row twice in one statement
+    let mut by_tg_id: HashMap<i64, CreateUserRequest> = HashMap::with_capacity(req.users.len());
+    for user in req.users {
+        validate_tg_id(user.tg_id)?;
+        if user.plan_id.is_some() {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "plan_id is not supported in bulk import"));
+        }
+        validate_source(user.source.as_deref())?;
+        by_tg_id.insert(user.tg_id, user);
+    }
+    let mut tg_ids = Vec::with_capacity(by_tg_id.len());
+    let mut usernames = Vec::with_capacity(by_tg_id.len());
+    let mut full_names = Vec::with_capacity(by_tg_id.len());
+    let mut sources = Vec::with_capacity(by_tg_id.len());
+    for user in by_tg_id.into_values() {
+        tg_ids.push(user.tg_id);
+        usernames.push(user.username);
+        full_names.push(user.full_name);
+        sources.push(user.source.unwrap_or_else(|| BULK_USER_SOURCE.to_string()));
+    }
+
+    let mut tx = state.pool.begin().await.map_err(db_error("bulk create users"))?;
+
+    let rows = sqlx::query_as::<_, (Uuid, i64, bool)>(
+        r#"
+        INSERT INTO users (tg_id, username, full_name, source)
+        SELECT * FROM UNNEST($1::bigint[], $2::text[], $3::text[], $4::text[])
+        ON CONFLICT (tg_id) DO UPDATE SET
+            username = COALESCE(EXCLUDED.username, users.username),
+            full_name = COALESCE(EXCLUDED.full_name, users.full_name)
+        RETURNING id, tg_id, (xmax = 0)
+        "#,
+    )
+    .bind(&tg_ids)
+    .bind(&usernames)
+    .bind(&full_names)
+    .bind(&sources)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(db_error("bulk create users"))?;
+
+    let user_ids: Vec<Uuid> = rows.iter().map(|(id, _, _)| *id).collect();
+    let latest: HashMap<Uuid, Uuid> = sqlx::query_as::<_, (Uuid, Uuid)>(
+        r#"
+        SELECT DISTINCT ON (user_id) user_id, xray_uuid
+        FROM subscriptions
+        WHERE user_id = ANY($1)
+        ORDER BY user_id, expire_date DESC
+        "#,
+    )
+    .bind(&user_ids)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(db_error("bulk create users"))?
+    .into_iter()
+    .collect();
+
+    tx.commit().await.map_err(db_error("bulk create users"))?;
+
+    let mut users: Vec<CreateUserResponse> = rows
+        .into_iter()
+        .map(|(id, tg_id, created)| CreateUserResponse {
+            id,
+            tg_id,
+            uuid: latest.get(&id).copied(),
+            created,
+        })
+        .collect();
+    users.sort_by_key(|u| u.tg_id);
+    if let Some(hook) = &state.user_created_webhook {
+        for user in users.iter().filter(|u| u.created) {
+            hook.notify(UserCreated {
+                id: user.id,
+                tg_id: user.tg_id,
+                uuid: user.uuid,
+            });
+        }
+    }
+    info!(
+        "Bulk import: {} users, {} new",
+        users.len(),
+        users.iter().filter(|u| u.created).count()
+    );
+    Ok(Json(BulkCreateResponse { users }))
+}
+
+// Creates the user's first subscription (trial or plan) unless they already have any; renewals
+// go through /subscriptions/extend. Placed on `server`, or the least loaded server when None.
+// Returns its Xray UUID, or None when nothing was created.
+pub async fn grant_subscription(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    tariff_id: i16,
+    minutes: i32,
+    kind: SubscriptionKind,
+    server: Option<Uuid>,
+) -> Result<Option<Uuid>, ApiError> {
+    // Same placement rule the bot uses for paid subscriptions, minus servers reserved for the other
+    // kind. A requested server has to pass it too.
+    let server_id: Option<Uuid> = sqlx::query_scalar!(
+        r#"
+        SELECT v.id AS "id!"
+        FROM view_server_load v
+        JOIN servers srv ON srv.id = v.id
+        WHERE srv.is_enabled AND v.slots_available > 0
+          AND (srv.only_kind IS NULL OR srv.only_kind = $1)
+          AND ($2::uuid IS NULL OR srv.id = $2)
+        ORDER BY v.load_percentage ASC
+        LIMIT 1
+        "#,
+        kind as SubscriptionKind,
+        server,
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(db_error("create subscription"))?;
+
+    let Some(server_id) = server_id else {
+        match server {
+            Some(server) => tracing::warn!("Server {} can't take user {}", server, user_id),
+            None => tracing::warn!("No server with free slots, user {} gets no subscription", user_id),
+        }
+        return Ok(None);
+    };
+
+    // The UUID is made by the database, and only when a row actually goes in. The email follows
+    // the same "user_{tariff}_{uuid prefix}" scheme the bot uses for Xray log identification.
+    let inserted: Option<Uuid> = sqlx::query_scalar!(
+        r#"
+        WITH fresh AS (SELECT gen_random_uuid() AS xray_uuid)
+        INSERT INTO subscriptions (user_id, server_id, tariff_id, xray_uuid, email, expire_date, kind)
+        SELECT $1, $2, $3, fresh.xray_uuid, 'user_' || $3::smallint || '_' || left(fresh.xray_uuid::text, 8),
+               now() + make_interval(mins => $4), $5
+        FROM fresh
+        WHERE NOT EXISTS (SELECT 1 FROM subscriptions WHERE user_id = $1)
+        RETURNING xray_uuid
+        "#,
+        user_id,
+        server_id,
+        tariff_id,
+        minutes,
+        kind as SubscriptionKind,
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(db_error("create subscription"))?;
+
+    if let Some(xray_uuid) = inserted {
+        info!(
+            "Granted {} minutes on tariff {} ({}) to user {} on server {}",
+            minutes, tariff_id, xray_uuid, user_id, server_id
+        );
+    }
+    Ok(inserted)
+}
+
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct UserListItem {
+    id: Uuid,
+    tg_id: i64,
+    // Latest subscription's Xray UUID
+    uuid: Option<Uuid>,
+    is_active: bool,
+    source: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct UserList {
+    total: i64,
+    users: Vec<UserListItem>,
+}
+
+// Admin listing, newest first
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<ListParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error("list users"))?;
+
+    let users = sqlx::query_as::<_, UserListItem>(
+        r#"
+        SELECT u.id, u.tg_id, latest.xray_uuid AS uuid, u.is_active, u.source, u.created_at
+        FROM users u
+        LEFT JOIN LATERAL (
+            SELECT s.xray_uuid
+            FROM subscriptions s
+            WHERE s.user_id = u.id
+            ORDER BY s.expire_date DESC
+            LIMIT 1
+        ) latest ON true
+        ORDER BY u.created_at DESC, u.id
+        LIMIT $1 OFFSET $2
+        "#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error("list users"))?;
+
+    Ok(Json(UserList { total, users }))
+}
+
+// Subscription fields are null when the user has never subscribed
+#[derive(Serialize)]
+pub struct UserStatus {
+    uuid: Option<Uuid>,
+    plan_id: Option<i16>,
+    status: Option<SubscriptionStatus>,
+    expire_date: Option<DateTime<Utc>>,
+    is_active: bool,
+    source: String,
+}
+
+// Looked up by Telegram id so the bot can show a user their current plan and expiry.
+// With several subscriptions the one expiring last is reported.
+pub async fn get_user(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tg_id): Path<i64>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let row = sqlx::query_as::<_, (Option<Uuid>, Option<i16>, Option<SubscriptionStatus>, Option<DateTime<Utc>>, bool, String)>(
+        r#"
+        SELECT s.xray_uuid, s.tariff_id, s.status, s.expire_date, u.is_active, u.source
+        FROM users u
+        LEFT JOIN subscriptions s ON s.user_id = u.id
+        WHERE u.tg_id = $1
+        ORDER BY s.expire_date DESC NULLS LAST
+        LIMIT 1
+        "#,
+    )
+    .bind(tg_id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(lookup_error("get user", "user not found"))?;
+
+    let (uuid, plan_id, status, expire_date, is_active, source) = row;
+    Ok(Json(UserStatus {
+        uuid,
+        plan_id,
+        status,
+        expire_date,
+        is_active,
+        source,
+    }))
+}
+
+// Path id is users.id here, unlike GET which takes the Telegram id.
+// Soft delete: the user row stays, but is_active = false and its active subscriptions are
+// cancelled, so agents drop the user on their next sync
+pub async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(db_error("delete user"))?;
+
+    sqlx::query_scalar::<_, Uuid>("UPDATE users SET is_active = false WHERE id = $1 RETURNING id")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(lookup_error("delete user", "user not found"))?;
+
+    let cancelled = sqlx::query(
+        "UPDATE subscriptions SET status = $2 WHERE user_id = $1 AND status = $3",
+    )
+    .bind(user_id)
+    .bind(SubscriptionStatus::Cancelled)
+    .bind(SubscriptionStatus::Active)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error("delete user"))?;
+
+    tx.commit()
+        .await
+        .map_err(db_error("delete user"))?;
+
+    info!(
+        "User {} deactivated, {} subscriptions cancelled",
+        user_id,
+        cancelled.rows_affected()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct ActiveState {
+    id: Uuid,
+    is_active: bool,
+}
+
+// Suspension only flips users.is_active; subscriptions are left alone. The trigger bumps
+// users.updated_at, so agents drop or re-add the user on their next sync, delta or full.
+async fn set_active(state: &AppState, user_id: Uuid, is_active: bool) -> Result<ActiveState, ApiError> {
+    sqlx::query_scalar::<_, Uuid>("UPDATE users SET is_active = $2 WHERE id = $1 RETURNING id")
+        .bind(user_id)
+        .bind(is_active)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(lookup_error("set user active", "user not found"))?;
+
+    info!("User {} {}", user_id, if is_active { "unsuspended" } else { "suspended" });
+    Ok(ActiveState { id: user_id, is_active })
+}
+
+// Reversible block, e.g. for abuse; path id is users.id as for DELETE
+pub async fn suspend_user(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(set_active(&state, user_id, false).await?))
+}
+
+pub async fn unsuspend_user(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(set_active(&state, user_id, true).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, call, post_json, ADMIN_TOKEN, TRIAL_TARIFF_ID};
+    use axum::response::IntoResponse;
+    use sqlx::PgPool;
+
+    #[test]
+    fn validate_tg_id_accepts_only_telegram_ids() {
+        for tg_id in [0, -1, i64::MIN, MAX_TG_ID + 1, i64::MAX] {
+            let err = validate_tg_id(tg_id).expect_err("out of range");
+            assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST, "tg_id {}", tg_id);
+        }
+        for tg_id in [1, 123_456_789, MAX_TG_ID] {
+            assert!(validate_tg_id(tg_id).is_ok(), "tg_id {}", tg_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn create_user_rejects_a_bad_tg_id_before_the_database() {
+        let state = test_util::state(test_util::unreachable_pool());
+
+        let res = call(&state, post_json("/api/v1/users", &[("x-admin-token", ADMIN_TOKEN)], serde_json::json!({ "tg_id": 0 }))).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(res.json()["error"]["message"], "tg_id must be positive");
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn create_user_grants_a_trial_once(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        test_util::server(&pool, "de-1", "agent-secret").await;
+        let admin = [("x-admin-token", ADMIN_TOKEN)];
+
+        let first = call(&state, post_json("/api/v1/users", &admin, serde_json::json!({ "tg_id": 42, "username": "alice" }))).await;
+        assert_eq!(first.status, StatusCode::OK);
+        let first = first.json();
+        assert_eq!(first["created"], true);
+        let uuid: Uuid = first["uuid"].as_str().unwrap().parse().unwrap();
+
+        let (tariff_id, kind): (i16, SubscriptionKind) =
+            sqlx::query_as("SELECT tariff_id, kind FROM subscriptions WHERE xray_uuid = $1")
+                .bind(uuid)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((tariff_id, kind), (TRIAL_TARIFF_ID, SubscriptionKind::Trial));
+
+        // A returning user keeps their subscription and name instead of getting a second trial
+        let again = call(&state, post_json("/api/v1/users", &admin, serde_json::json!({ "tg_id": 42 }))).await.json();
+        assert_eq!(again["created"], false);
+        assert_eq!(again["id"], first["id"]);
+        assert_eq!(again["uuid"], first["uuid"]);
+
+        let (subscriptions, username): (i64, Option<String>) = sqlx::query_as(
+            "SELECT (SELECT count(*) FROM subscriptions), (SELECT username FROM users WHERE tg_id = 42)",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!((subscriptions, username.as_deref()), (1, Some("alice")));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn create_user_with_a_plan_starts_a_paid_subscription(pool: PgPool) {
+        let state = test_util::state(pool.clone());
+        test_util::server(&pool, "de-1", "agent-secret").await;
+
+        let body = serde_json::json!({ "tg_id": 42, "plan_id": 2 });
+        let res = call(&state, post_json("/api/v1/users", &[("x-admin-token", ADMIN_TOKEN)], body)).await.json();
+        let uuid: Uuid = res["uuid"].as_str().unwrap().parse().unwrap();
+
+        let (tariff_id, kind): (i16, SubscriptionKind) =
+            sqlx::query_as("SELECT tariff_id, kind FROM subscriptions WHERE xray_uuid = $1")
+                .bind(uuid)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((tariff_id, kind), (2, SubscriptionKind::Paid));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn create_user_leaves_nothing_behind_when_the_trial_fails(pool: PgPool) {
+        // The subscription insert then breaks its tariff foreign key, after the user went in
+        let state = AppState {
+            trial_tariff_id: 999,
+            ..test_util::state(pool.clone())
+        };
+        test_util::server(&pool, "de-1", "agent-secret").await;
+
+        let res = call(&state, post_json("/api/v1/users", &[("x-admin-token", ADMIN_TOKEN)], serde_json::json!({ "tg_id": 42 }))).await;
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let users: i64 = sqlx::query_scalar("SELECT count(*) FROM users").fetch_one(&pool).await.unwrap();
+        assert_eq!(users, 0);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn create_user_requires_the_admin_token(pool: PgPool) {
+        let state = test_util::state(pool);
+
+        let res = call(&state, post_json("/api/v1/users", &[("x-admin-token", "wrong")], serde_json::json!({ "tg_id": 42 }))).await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+}
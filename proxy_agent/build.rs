@@ -0,0 +1,22 @@
+use std::path::Path;
+use std::process::Command;
+
+// GIT_SHA for /status, the heartbeat and logs. Docker builds have no .git and pass it as a build
+// arg instead; without either it's "unknown".
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    for path in ["../.git/HEAD", "../.git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .or_else(|| {
+            let out = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+            out.status.success().then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_SHA={}", sha);
+}
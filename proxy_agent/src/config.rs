@@ -0,0 +1,343 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+use tracing::{info, warn};
+
+use crate::{
+    apply_vless_flows, normalize_grpc_addr, parse_inbound, parse_shadowsocks_cipher, shadowsocks, AccountSettings,
+    GrpcTarget, Inbound, Protocol, VlessSettings, KNOWN_VLESS_FLOWS,
+};
+
+const DEFAULT_SYNC_INTERVAL_SECS: u64 = 30;
+const DEFAULT_XRAY_GRPC_ADDR: &str = "http://127.0.0.1:8080";
+const DEFAULT_INBOUND_TAG: &str = "inbound-vless";
+const DEFAULT_VLESS_FLOW: &str = "xtls-rprx-vision";
+const DEFAULT_VLESS_ENCRYPTION: &str = "none";
+const DEFAULT_USAGE_REPORT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_VERIFY_INTERVAL_SECS: u64 = 300;
+// Failed syncs in a row before the log escalates to error level
+const DEFAULT_SYNC_FAILURE_ALERT: u32 = 5;
+// Xray is normally local, so a few seconds is plenty; raise for remote (TLS) API endpoints
+const DEFAULT_XRAY_CONNECT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_XRAY_REQUEST_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_XRAY_KEEPALIVE_SECS: u64 = 30;
+// Bounds every control plane call so a hung control plane can't stall the sync loop
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_HTTP_CONNECT_TIMEOUT_SECS: u64 = 5;
+// alter_inbound calls kept in flight at once while reconciling
+const DEFAULT_XRAY_CONCURRENCY: usize = 8;
+// Under the 10s docker stop gives before SIGKILL
+const DEFAULT_PURGE_TIMEOUT_SECS: u64 = 8;
+
+// Everything the agent reads from the environment, parsed and checked before it touches Xray
+// or the control plane, so a typo fails the start instead of quietly falling back to a default.
+// LOG_FORMAT and RUST_LOG are the exception: logging is set up before this is read.
+pub struct Config {
+    // Comma-separated replicas, tried in order on every request
+    pub control_plane_urls: Vec<String>,
+    pub server_secret: String,
+    // Shared with the control plane; when set, unsigned or tampered sync responses are not applied
+    pub sync_signing_key: Option<Vec<u8>>,
+    // XRAY_INSTANCES, or the single instance at XRAY_GRPC_ADDR
+    pub xray_targets: Vec<(String, GrpcTarget)>,
+    pub state_file: Option<PathBuf>,
+    pub inbounds: Vec<Inbound>,
+    pub accounts: AccountSettings,
+    // Fetch full syncs as NDJSON from /sync/stream; for very large servers
+    pub sync_stream: bool,
+    // Walk through syncs without touching Xray, the control plane or the state file
+    pub dry_run: bool,
+    pub xray_concurrency: usize,
+    // Give up (exit non-zero) when Xray can't be reached at startup for this long; None waits forever
+    pub xray_connect_max_wait: Option<Duration>,
+    pub sync_interval: Duration,
+    // Spreads a fleet's sync requests so agents don't hit the control plane in lockstep
+    pub sync_jitter_pct: u32,
+    // 0 disables traffic reporting
+    pub usage_interval_secs: u64,
+    // 0 (default) disables online-device reports; they need statsUserOnline in Xray's policy
+    pub online_interval_secs: u64,
+    // 0 disables heartbeats
+    pub heartbeat_interval_secs: u64,
+    // How often Xray's user lists are checked against ours; 0 disables
+    pub verify_interval_secs: u64,
+    pub sync_failure_alert: u32,
+    // Exit so the orchestrator restarts us once syncs keep failing; 0 (default) never exits
+    pub sync_exit_after: u32,
+    // On SIGTERM/SIGINT, remove every provisioned user from Xray before exiting. Off by default:
+    // the signals then keep their usual behavior and users survive an agent restart.
+    pub purge_on_shutdown: bool,
+    pub purge_timeout: Duration,
+    pub http_connect_timeout: Duration,
+    pub http_timeout: Duration,
+    // Unset (default) means no HTTP listener at all, and metrics go nowhere
+    pub health_addr: Option<String>,
+    // /status lists fleet details, so it can be locked down separately from /readyz
+    pub status_token: Option<String>,
+}
+
+fn string(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+// Unset or empty is None; anything else has to parse
+fn var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    string(name)
+        .map(|v| {
+            v.trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("{} {:?} is invalid: {}", name, v, e))
+        })
+        .transpose()
+}
+
+fn var_or<T: FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    Ok(var(name)?.unwrap_or(default))
+}
+
+// A positive number of seconds; 0 also means the default
+fn secs_or(name: &str, default: u64) -> Result<Duration> {
+    let secs: u64 = var_or(name, default)?;
+    Ok(Duration::from_secs(if secs > 0 { secs } else { default }))
+}
+
+fn flag(name: &str) -> Result<bool> {
+    match string(name).as_deref().map(str::trim) {
+        None | Some("false" | "0") => Ok(false),
+        Some("true" | "1") => Ok(true),
+        Some(other) => anyhow::bail!("{} {:?} is invalid, expected true or false", name, other),
+    }
+}
+
+fn set_or_unset<T>(value: &Option<T>) -> &'static str {
+    if value.is_some() {
+        "set"
+    } else {
+        "unset"
+    }
+}
+
+// TLS is on when XRAY_GRPC_CA is set; a client cert/key pair adds mTLS.
+// Without a CA we stay on plaintext, which is fine for a local or host-only API.
+fn grpc_target() -> Result<GrpcTarget> {
+    let addr = match string("XRAY_GRPC_ADDR") {
+        Some(raw) => normalize_grpc_addr(&raw).with_context(|| format!("XRAY_GRPC_ADDR {:?}", raw))?,
+        None => DEFAULT_XRAY_GRPC_ADDR.to_string(),
+    };
+    let mut target = GrpcTarget {
+        addr,
+        tls: None,
+        connect_timeout: secs_or("XRAY_CONNECT_TIMEOUT_SECS", DEFAULT_XRAY_CONNECT_TIMEOUT_SECS)?,
+        request_timeout: secs_or("XRAY_REQUEST_TIMEOUT_SECS", DEFAULT_XRAY_REQUEST_TIMEOUT_SECS)?,
+        keepalive: secs_or("XRAY_KEEPALIVE_SECS", DEFAULT_XRAY_KEEPALIVE_SECS)?,
+    };
+    let read = |var: &str| -> Result<Option<Vec<u8>>> {
+        match string(var) {
+            Some(path) => std::fs::read(&path)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("{} ({}): {}", var, path, e)),
+            None => Ok(None),
+        }
+    };
+    let ca = read("XRAY_GRPC_CA")?;
+    let client_cert = read("XRAY_GRPC_CLIENT_CERT")?;
+    let client_key = read("XRAY_GRPC_CLIENT_KEY")?;
+
+    let Some(ca) = ca else {
+        anyhow::ensure!(
+            client_cert.is_none() && client_key.is_none(),
+            "XRAY_GRPC_CLIENT_CERT/XRAY_GRPC_CLIENT_KEY need XRAY_GRPC_CA"
+        );
+        return Ok(target);
+    };
+    // tonic only does TLS for https:// endpoints and would otherwise silently talk plaintext
+    anyhow::ensure!(
+        target.addr.starts_with("https://"),
+        "XRAY_GRPC_CA is set but XRAY_GRPC_ADDR is not https://"
+    );
+
+    let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+    match (client_cert, client_key) {
+        (Some(cert), Some(key)) => tls = tls.identity(Identity::from_pem(cert, key)),
+        (None, None) => {}
+        _ => anyhow::bail!("XRAY_GRPC_CLIENT_CERT and XRAY_GRPC_CLIENT_KEY must be set together"),
+    }
+    target.tls = Some(tls);
+    Ok(target)
+}
+
+// XRAY_INSTANCES="premium=127.0.0.1:8081,standard=127.0.0.1:8080": name=addr pairs, first one
+// being the default. Unset means a single instance named "default" at XRAY_GRPC_ADDR. TLS and
+// timeout settings from `template` apply to every instance.
+fn xray_targets(template: &GrpcTarget) -> Result<Vec<(String, GrpcTarget)>> {
+    let Some(raw) = string("XRAY_INSTANCES") else {
+        return Ok(vec![("default".to_string(), template.clone())]);
+    };
+    let mut targets: Vec<(String, GrpcTarget)> = Vec::new();
+    for spec in raw.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (name, addr) = spec
+            .split_once('=')
+            .with_context(|| format!("XRAY_INSTANCES entry {:?} is not name=addr", spec))?;
+        let name = name.trim().to_string();
+        anyhow::ensure!(!name.is_empty(), "XRAY_INSTANCES entry {:?} has no name", spec);
+        anyhow::ensure!(
+            targets.iter().all(|(n, _)| *n != name),
+            "XRAY_INSTANCES names {} twice",
+            name
+        );
+        let addr = normalize_grpc_addr(addr).with_context(|| format!("XRAY_INSTANCES {}", name))?;
+        anyhow::ensure!(
+            template.tls.is_none() || addr.starts_with("https://"),
+            "XRAY_GRPC_CA is set but XRAY_INSTANCES {} is not https://",
+            name
+        );
+        targets.push((name, GrpcTarget { addr, ..template.clone() }));
+    }
+    anyhow::ensure!(!targets.is_empty(), "XRAY_INSTANCES has no instances");
+    Ok(targets)
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let control_plane_urls: Vec<String> = string("CONTROL_PLANE_URL")
+            .context("CONTROL_PLANE_URL must be set")?
+            .split(',')
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+            .map(String::from)
+            .collect();
+        anyhow::ensure!(!control_plane_urls.is_empty(), "CONTROL_PLANE_URL has no URLs");
+        let server_secret = string("SERVER_SECRET").context("SERVER_SECRET must be set")?;
+
+        // Comma-separated "tag[:protocol]", e.g. "inbound-vless,inbound-vmess:vmess,inbound-ss:shadowsocks"
+        let mut inbounds: Vec<Inbound> = std::env::var("XRAY_INBOUND_TAG")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(parse_inbound)
+            .collect::<Result<_>>()
+            .context("XRAY_INBOUND_TAG")?;
+        if inbounds.is_empty() {
+            inbounds.push(parse_inbound(DEFAULT_INBOUND_TAG)?);
+        }
+        if let Some(spec) = string("VLESS_FLOWS") {
+            apply_vless_flows(&mut inbounds, &spec)?;
+        }
+        let vless = VlessSettings {
+            flow: std::env::var("VLESS_FLOW").unwrap_or_else(|_| DEFAULT_VLESS_FLOW.into()),
+            encryption: std::env::var("VLESS_ENCRYPTION").unwrap_or_else(|_| DEFAULT_VLESS_ENCRYPTION.into()),
+        };
+        if !KNOWN_VLESS_FLOWS.contains(&vless.flow.as_str()) {
+            warn!("VLESS_FLOW {:?} is not a known Xray flow, clients may fail to connect", vless.flow);
+        }
+        // Has to match the method clients put in their ss:// links
+        let shadowsocks_cipher = if inbounds.iter().any(|i| i.protocol == Protocol::Shadowsocks) {
+            let method = string("SS_METHOD").context("SS_METHOD is required for a shadowsocks inbound")?;
+            parse_shadowsocks_cipher(&method)?
+        } else {
+            shadowsocks::CipherType::Unknown
+        };
+
+        let template = grpc_target()?;
+        Ok(Self {
+            control_plane_urls,
+            server_secret,
+            sync_signing_key: string("SYNC_SIGNING_KEY").map(String::into_bytes),
+            xray_targets: xray_targets(&template)?,
+            state_file: string("STATE_FILE").map(PathBuf::from),
+            inbounds,
+            accounts: AccountSettings {
+                vless,
+                shadowsocks_cipher,
+            },
+            sync_stream: flag("SYNC_STREAM")?,
+            dry_run: flag("DRY_RUN")?,
+            xray_concurrency: Some(var_or("XRAY_CONCURRENCY", DEFAULT_XRAY_CONCURRENCY)?)
+                .filter(|n| *n > 0)
+                .unwrap_or(DEFAULT_XRAY_CONCURRENCY),
+            xray_connect_max_wait: var::<u64>("XRAY_CONNECT_MAX_WAIT_SECS")?
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            sync_interval: secs_or("SYNC_INTERVAL_SECS", DEFAULT_SYNC_INTERVAL_SECS)?,
+            sync_jitter_pct: var_or("SYNC_JITTER_PCT", 0u32)?.min(100),
+            usage_interval_secs: var_or("USAGE_REPORT_INTERVAL_SECS", DEFAULT_USAGE_REPORT_INTERVAL_SECS)?,
+            online_interval_secs: var_or("ONLINE_REPORT_INTERVAL_SECS", 0)?,
+            heartbeat_interval_secs: var_or("HEARTBEAT_INTERVAL_SECS", DEFAULT_HEARTBEAT_INTERVAL_SECS)?,
+            verify_interval_secs: var_or("VERIFY_INTERVAL_SECS", DEFAULT_VERIFY_INTERVAL_SECS)?,
+            sync_failure_alert: Some(var_or("SYNC_FAILURE_ALERT", DEFAULT_SYNC_FAILURE_ALERT)?)
+                .filter(|n| *n > 0)
+                .unwrap_or(DEFAULT_SYNC_FAILURE_ALERT),
+            sync_exit_after: var_or("SYNC_EXIT_AFTER_FAILURES", 0)?,
+            purge_on_shutdown: flag("PURGE_ON_SHUTDOWN")?,
+            purge_timeout: secs_or("PURGE_TIMEOUT_SECS", DEFAULT_PURGE_TIMEOUT_SECS)?,
+            http_connect_timeout: secs_or("HTTP_CONNECT_TIMEOUT_SECS", DEFAULT_HTTP_CONNECT_TIMEOUT_SECS)?,
+            http_timeout: secs_or("HTTP_TIMEOUT_SECS", DEFAULT_HTTP_TIMEOUT_SECS)?,
+            health_addr: string("AGENT_HEALTH_ADDR"),
+            status_token: string("AGENT_STATUS_TOKEN"),
+        })
+    }
+
+    // The effective settings, with secrets reduced to whether they are set
+    pub fn log(&self) {
+        let targets: Vec<String> = self
+            .xray_targets
+            .iter()
+            .map(|(name, t)| format!("{}={}{}", name, t.addr, if t.tls.is_some() { " (TLS)" } else { "" }))
+            .collect();
+        let inbounds: Vec<String> = self
+            .inbounds
+            .iter()
+            .map(|i| match &i.flow {
+                Some(flow) => format!("{}:{}(flow={:?})", i.tag, i.protocol.name(), flow),
+                None => format!("{}:{}", i.tag, i.protocol.name()),
+            })
+            .collect();
+        info!(
+            "Config: control_plane_urls={:?}, server_secret=set, sync_signing_key={}, sync_stream={}, dry_run={}, state_file={:?}",
+            self.control_plane_urls,
+            set_or_unset(&self.sync_signing_key),
+            self.sync_stream,
+            self.dry_run,
+            self.state_file
+        );
+        info!(
+            "Config: xray={:?}, inbounds={:?}, vless_flow={:?}, xray_concurrency={}, xray_connect_max_wait={:?}",
+            targets,
+            inbounds,
+            self.accounts.vless.flow,
+            self.xray_concurrency,
+            self.xray_connect_max_wait
+        );
+        info!(
+            "Config: sync_interval={:?} (jitter {}%), usage={}s, online={}s, heartbeat={}s, verify={}s (0 = off), \
+             sync_failure_alert={}, sync_exit_after={}",
+            self.sync_interval,
+            self.sync_jitter_pct,
+            self.usage_interval_secs,
+            self.online_interval_secs,
+            self.heartbeat_interval_secs,
+            self.verify_interval_secs,
+            self.sync_failure_alert,
+            self.sync_exit_after
+        );
+        info!(
+            "Config: purge_on_shutdown={} (timeout {:?}), http_timeout={:?} (connect {:?}), health_addr={:?}, status_token={}",
+            self.purge_on_shutdown,
+            self.purge_timeout,
+            self.http_timeout,
+            self.http_connect_timeout,
+            self.health_addr,
+            set_or_unset(&self.status_token)
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::Utc;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::heartbeat::AgentStatus;
+
+// Configuration echoed by /status, fixed for the agent's lifetime
+pub struct StaticInfo {
+    pub grpc_addr: String,
+    pub inbound_tags: Vec<String>,
+    // When set, /status requires it in X-Status-Token
+    pub status_token: Option<String>,
+}
+
+#[derive(Clone)]
+struct HealthState {
+    status: watch::Receiver<AgentStatus>,
+    metrics: PrometheusHandle,
+    info: Arc<StaticInfo>,
+}
+
+// Ready once the first sync has gone through, and only while the Xray connection isn't
+// known to be dead. Either way the body says which part is missing.
+async fn readyz(State(state): State<HealthState>) -> impl IntoResponse {
+    let current = state.status.borrow().clone();
+    let status = if current.last_sync_at.is_none() {
+        "not_synced"
+    } else if !current.xray_ok {
+        "xray_unavailable"
+    } else {
+        "ok"
+    };
+
+    let code = if status == "ok" {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::json!({
+        "status": status,
+        "active_count": current.active_count,
+        "last_sync_at": current.last_sync_at,
+    });
+    (code, Json(body))
+}
+
+// Prometheus text exposition. The user count gauge is taken from the published status here
+// rather than kept up to date at every place the sync loop touches its user map.
+async fn metrics_handler(State(state): State<HealthState>) -> impl IntoResponse {
+    // Named after UUIDs, though the agent tracks users by email; it's one entry per Xray user
+    metrics::gauge!("local_uuids_size").set(state.status.borrow().active_count as f64);
+    state.metrics.render()
+}
+
+// Operator diagnostics: when the last sync went through, what it changed, and what we talk to
+async fn status_handler(State(state): State<HealthState>, headers: HeaderMap) -> Response {
+    if let Some(expected) = &state.info.status_token {
+        let given = headers.get("X-Status-Token").map(|v| v.as_bytes()).unwrap_or_default();
+        if !bool::from(given.ct_eq(expected.as_bytes())) {
+            return (StatusCode::UNAUTHORIZED, "invalid status token").into_response();
+        }
+    }
+
+    let current = state.status.borrow().clone();
+    Json(serde_json::json!({
+        "last_successful_sync": current.last_sync_at,
+        "seconds_since_sync": current.last_sync_at.map(|at| (Utc::now() - at).num_seconds()),
+        "last_cycle_added": current.last_cycle_added,
+        "last_cycle_removed": current.last_cycle_removed,
+        "local_uuids_size": current.active_count,
+        "xray_ok": current.xray_ok,
+        "version": crate::VERSION,
+        "git_sha": crate::GIT_SHA,
+        "grpc_addr": state.info.grpc_addr,
+        "inbound_tags": state.info.inbound_tags,
+    }))
+    .into_response()
+}
+
+// Binds up front so a bad AGENT_HEALTH_ADDR fails startup, then serves in the background
+pub async fn spawn_server(
+    addr: &str,
+    status: watch::Receiver<AgentStatus>,
+    metrics: PrometheusHandle,
+    info: StaticInfo,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Health endpoint listening on {}", listener.local_addr()?);
+
+    let app = Router::new()
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics_handler))
+        .route("/status", get(status_handler))
+        .with_state(HealthState {
+            status,
+            metrics,
+            info: Arc::new(info),
+        });
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!("Health endpoint stopped: {}", e);
+        }
+    });
+    Ok(())
+}
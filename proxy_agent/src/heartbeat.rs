@@ -0,0 +1,70 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+// What the sync loop publishes for the heartbeat task and the health endpoint
+#[derive(Debug, Clone, Default)]
+pub struct AgentStatus {
+    pub active_count: usize,
+    pub last_sync_at: Option<DateTime<Utc>>,
+    // False while the Xray connection is considered dead and awaiting reconnect
+    pub xray_ok: bool,
+    // Users added and removed by the last successful sync
+    pub last_cycle_added: usize,
+    pub last_cycle_removed: usize,
+}
+
+#[derive(Serialize)]
+struct Heartbeat<'a> {
+    // "0.1.0 (abc123def456)": crate version and git commit
+    version: &'a str,
+    active_count: usize,
+    last_sync_at: Option<DateTime<Utc>>,
+}
+
+async fn post_heartbeat(
+    client: &reqwest::Client,
+    base_url: &str,
+    server_secret: &str,
+    status: &AgentStatus,
+) -> Result<()> {
+    let url = format!("{}/api/internal/heartbeat", base_url.trim_end_matches('/'));
+    let res = client
+        .post(&url)
+        .header("X-Server-Secret", server_secret)
+        .json(&Heartbeat {
+            version: &format!("{} ({})", crate::VERSION, crate::GIT_SHA),
+            active_count: status.active_count,
+            last_sync_at: status.last_sync_at,
+        })
+        .send()
+        .await?;
+    anyhow::ensure!(res.status().is_success(), "heartbeat returned {}", res.status());
+    Ok(())
+}
+
+// Background task: tell the control plane how many users we have provisioned, so it can spot drift
+pub async fn heartbeat_loop(
+    status: watch::Receiver<AgentStatus>,
+    http_client: reqwest::Client,
+    control_plane_urls: Vec<String>,
+    server_secret: String,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let current = status.borrow().clone();
+        let sent = crate::first_success(&control_plane_urls, "Heartbeat", |url| {
+            post_heartbeat(&http_client, url, &server_secret, &current)
+        })
+        .await;
+        match sent {
+            Ok(()) => debug!("Heartbeat sent: {} users", current.active_count),
+            Err(e) => warn!("Heartbeat failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::{Context, Result};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::{AccountSettings, GrpcTarget, Inbound, UserConfig, XrayClient};
+
+// One Xray process on this host and the name tariffs route users to it by (xray_target)
+pub struct XrayInstance {
+    pub name: String,
+    pub client: XrayClient,
+}
+
+// Every Xray process the agent manages. The first one is the default: it gets users without a
+// target, and with a single instance (the usual setup) it gets everyone regardless of target.
+pub struct XrayInstances {
+    pub instances: Vec<XrayInstance>,
+    // Upper bound on concurrent alter_inbound calls during add/remove batches, across all instances
+    pub concurrency: usize,
+}
+
+impl XrayInstances {
+    // Connects to every instance, retrying each with capped exponential backoff until it answers
+    // or, when `max_wait` is set, until it has been failing that long
+    pub async fn connect(
+        targets: &[(String, GrpcTarget)],
+        inbounds: Vec<Inbound>,
+        accounts: AccountSettings,
+        concurrency: usize,
+        dry_run: bool,
+        max_wait: Option<Duration>,
+    ) -> Result<Self> {
+        let (initial_secs, max_secs) = crate::XRAY_CONNECT_BACKOFF_SECS;
+        let mut instances = Vec::new();
+        for (name, target) in targets {
+            let started = Instant::now();
+            let mut attempts: u32 = 0;
+            let mut delay = Duration::from_secs(initial_secs);
+            let client = loop {
+                match XrayClient::new(target, inbounds.clone(), accounts.clone(), dry_run).await {
+                    Ok(c) => break c,
+                    Err(e) => {
+                        attempts += 1;
+                        let down = started.elapsed();
+                        if max_wait.is_some_and(|max| down >= max) {
+                            anyhow::bail!(
+                                "giving up on Xray {} at {} after {} attempts over {}s: {:#}",
+                                name,
+                                target.addr,
+                                attempts,
+                                down.as_secs(),
+                                e
+                            );
+                        }
+                        warn!(
+                            "Failed to connect to Xray {} at {} (attempt {}, failing for {}s): {:#}. Retrying in {} seconds...",
+                            name,
+                            target.addr,
+                            attempts,
+                            down.as_secs(),
+                            e,
+                            delay.as_secs()
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(Duration::from_secs(max_secs));
+                    }
+                }
+            };
+            if attempts > 0 {
+                info!(
+                    "Connected to Xray {} at {} after {} failed attempts over {}s",
+                    name,
+                    target.addr,
+                    attempts,
+                    started.elapsed().as_secs()
+                );
+            } else {
+                info!("Connected to Xray {} at {}", name, target.addr);
+            }
+            instances.push(XrayInstance {
+                name: name.clone(),
+                client,
+            });
+        }
+        Ok(Self { instances, concurrency })
+    }
+
+    // Index of the instance carrying users with this target, None for a name we don't have
+    pub fn index(&self, target: Option<&str>) -> Option<usize> {
+        match target {
+            _ if self.instances.len() == 1 => Some(0),
+            None => Some(0),
+            Some(name) => self.instances.iter().position(|i| i.name == name),
+        }
+    }
+
+    pub async fn add_user(&self, cfg: &UserConfig) -> Result<()> {
+        let Some(i) = self.index(cfg.target.as_deref()) else {
+            anyhow::bail!("unknown Xray instance {:?}, not in XRAY_INSTANCES", cfg.target.as_deref().unwrap_or_default());
+        };
+        self.instances[i].client.add_user(cfg).await
+    }
+
+    // Whether the user's tags put them on a different set of our inbounds than `old` did
+    pub fn inbounds_changed(&self, old: &UserConfig, new: &UserConfig) -> bool {
+        self.instances[0]
+            .client
+            .inbounds
+            .iter()
+            .any(|i| old.on_inbound(&i.tag) != new.on_inbound(&i.tag))
+    }
+
+    // Takes the user off the inbounds `new` no longer lists and onto the ones it added, leaving
+    // the rest alone. Both are on the same instance; a changed target goes through remove/add.
+    pub async fn move_inbounds(&self, old: &UserConfig, new: &UserConfig) -> Result<()> {
+        let Some(i) = self.index(new.target.as_deref()) else {
+            anyhow::bail!("unknown Xray instance {:?}, not in XRAY_INSTANCES", new.target.as_deref().unwrap_or_default());
+        };
+        let client = &self.instances[i].client;
+        client
+            .remove_user_from(&new.email, |inbound| old.on_inbound(&inbound.tag) && !new.on_inbound(&inbound.tag))
+            .await?;
+        client
+            .add_user_on(new, |inbound| new.on_inbound(&inbound.tag) && !old.on_inbound(&inbound.tag))
+            .await
+    }
+
+    // A target we no longer know (XRAY_INSTANCES changed since the user was added) is removed
+    // from every instance, which tolerates the ones that never had the user
+    pub async fn remove_user(&self, email: &str, target: Option<&str>) -> Result<()> {
+        match self.index(target) {
+            Some(i) => self.instances[i].client.remove_user(email).await,
+            None => {
+                for instance in &self.instances {
+                    instance.client.remove_user(email).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn needs_reconnect(&self) -> bool {
+        self.instances.iter().any(|i| i.client.needs_reconnect())
+    }
+
+    // Reconnects the instances whose connection looks dead; stops at the first failure
+    pub async fn reconnect(&mut self) -> Result<()> {
+        for instance in self.instances.iter_mut().filter(|i| i.client.needs_reconnect()) {
+            warn!("Xray connection looks dead, reconnecting to {} at {}", instance.name, instance.client.target.addr);
+            instance
+                .client
+                .reconnect()
+                .await
+                .with_context(|| format!("Xray {}", instance.name))?;
+            info!("Reconnected to Xray {} at {}", instance.name, instance.client.target.addr);
+        }
+        Ok(())
+    }
+
+    pub async fn verify_inbounds(&self) -> Result<()> {
+        for instance in &self.instances {
+            instance
+                .client
+                .verify_inbounds()
+                .await
+                .with_context(|| format!("Xray {}", instance.name))?;
+        }
+        Ok(())
+    }
+
+    // (instance index, tag) of every inbound that came back
+    pub async fn check_inbounds(&self) -> Vec<(usize, String)> {
+        let mut returned = Vec::new();
+        for (i, instance) in self.instances.iter().enumerate() {
+            returned.extend(instance.client.check_inbounds().await.into_iter().map(|tag| (i, tag)));
+        }
+        returned
+    }
+
+    // "name=addr" for every instance, for logs and /status
+    pub fn describe(&self) -> String {
+        if let [only] = self.instances.as_slice() {
+            return only.client.target.addr.clone();
+        }
+        self.instances
+            .iter()
+            .map(|i| format!("{}={}", i.name, i.client.target.addr))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
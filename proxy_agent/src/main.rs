@@ -1,192 +1,2296 @@
-use anyhow::Result;
+mod config;
+mod health;
+mod heartbeat;
+mod instances;
+#[cfg(test)]
+mod mock_xray;
+mod stats;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use hmac::{Hmac, Mac};
+use metrics_exporter_prometheus::PrometheusBuilder;
 use prost::Message;
 use prost::Name; 
-use serde::Deserialize;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet}; // Use HashMap to track UUID -> Level
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
-use tonic::transport::{Channel, Endpoint};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tracing::{debug, error, info, warn, Instrument};
+
+use instances::XrayInstances;
 
 // Ensure your generated/imported modules match
 use xray_core::app::proxyman::command::{
     handler_service_client::HandlerServiceClient, AddUserOperation, AlterInboundRequest,
-    RemoveUserOperation,
+    GetInboundUserRequest, RemoveUserOperation,
 };
 use xray_core::common::protocol::User;
 use xray_core::common::serial::TypedMessage;
-use xray_core::proxy::vless::Account;
+use xray_core::proxy::{shadowsocks, trojan, vless, vmess};
 
-const SYNC_INTERVAL_SECS: u64 = 30;
-const XRAY_CONNECT_RETRY_SECS: u64 = 10;
-const DEFAULT_INBOUND_TAG: &str = "inbound-vless";
+// Crate version and the commit it was built from (build.rs); in the heartbeat, /status and logs
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_SHA: &str = env!("GIT_SHA");
+// Startup connect retries back off from the first value, doubling up to the second
+const XRAY_CONNECT_BACKOFF_SECS: (u64, u64) = (1, 60);
+const INITIAL_SYNC_RETRY_SECS: u64 = 5;
+// Newest sync format we understand, sent as Accept-Version. Responses without a version are v1.
+const SYNC_FORMAT_VERSION: u32 = 2;
+// Longest Retry-After we go along with; a larger one is likely a misconfigured proxy
+const MAX_RETRY_AFTER_SECS: u64 = 600;
+// Flows Xray accepts on a VLESS inbound; empty means plain TLS/no Vision
+const KNOWN_VLESS_FLOWS: [&str; 3] = ["", "xtls-rprx-vision", "xtls-rprx-vision-udp443"];
+// Backoff between retries of a transiently failing alter_inbound call
+const ALTER_RETRY_DELAYS_SECS: [u64; 3] = [1, 2, 4];
+// Consecutive connection-level failures after which the channel is considered dead
+const RECONNECT_AFTER_FAILURES: u32 = 3;
+// Clock difference to the control plane's database past which we warn; expiry removals are off
+// by that much
+const CLOCK_SKEW_WARN_SECS: i64 = 5;
 
 // New Structure matches Control Plane
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct UserConfig {
+    // Credential: the VLESS/VMess id, Trojan and Shadowsocks password
     uuid: String,
     level: u32,
+    // Identity in Xray (logs, stats, removal). Control planes that don't send one get the UUID.
+    #[serde(default)]
     email: String,
+    // Present when syncing with ?detailed=true; lets us remove the user right at expiry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expire_date: Option<DateTime<Utc>>,
+    // Name of the Xray instance (XRAY_INSTANCES) that carries this user; None = the default one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    // Inbound tags this user belongs on; None = every configured inbound. Tags we don't have are
+    // ignored. Kept in local_users as what the user is provisioned on, so a change moves them
+    // on or off single inbounds instead of re-adding them everywhere.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+}
+
+impl UserConfig {
+    fn on_inbound(&self, tag: &str) -> bool {
+        self.tags.as_ref().is_none_or(|tags| tags.iter().any(|t| t == tag))
+    }
 }
 
 #[derive(Deserialize)]
 struct SyncResponse {
+    // Sync format; None for v1, which predates the field
+    #[serde(default)]
+    version: Option<u32>,
     users: Vec<UserConfig>,
+    // Older control planes don't send a cursor; we then stay on full syncs
+    #[serde(default)]
+    cursor: Option<String>,
+    // The control plane's database clock; older control planes don't send it
+    #[serde(default)]
+    server_time: Option<DateTime<Utc>>,
+    // Users an operator asked to have removed and re-added
+    #[serde(default)]
+    resync: Vec<String>,
+    // From the ETag header; sent back as If-None-Match on the next full sync
+    #[serde(skip)]
+    etag: Option<String>,
+}
+
+// Lines of /sync/stream: users, then the trailer, then (when signing) the signature
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StreamLine {
+    End { end: StreamTrailer },
+    Signature { signature: String },
+    User(UserConfig),
+}
+
+#[derive(Deserialize)]
+struct StreamTrailer {
+    #[serde(default)]
+    version: Option<u32>,
+    cursor: Option<String>,
+    #[serde(default)]
+    server_time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    resync: Vec<String>,
+    count: usize,
+}
+
+enum SyncResult {
+    Full(SyncResponse),
+    // 304: the users are the same as when we got the ETag we sent
+    Unchanged { cursor: Option<String> },
+}
+
+#[derive(Deserialize)]
+struct DeltaResponse {
+    #[serde(default)]
+    version: Option<u32>,
+    added: Vec<UserConfig>,
+    removed: Vec<String>,
+    cursor: String,
+    #[serde(default)]
+    server_time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    resync: Vec<String>,
+}
+
+// Account type an inbound expects; users get the same identity on every protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Vless,
+    Vmess,
+    Trojan,
+    Shadowsocks,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "vless" => Ok(Protocol::Vless),
+            "vmess" => Ok(Protocol::Vmess),
+            "trojan" => Ok(Protocol::Trojan),
+            "shadowsocks" | "ss" => Ok(Protocol::Shadowsocks),
+            other => anyhow::bail!("unsupported inbound protocol: {}", other),
+        }
+    }
+}
+
+// Must match the server's inbound profile: Reality+Vision wants the default flow, plain TLS an empty one
+#[derive(Debug, Clone)]
+struct VlessSettings {
+    flow: String,
+    encryption: String,
+}
+
+// Per-protocol account parameters that come from the environment rather than the control plane
+#[derive(Debug, Clone)]
+struct AccountSettings {
+    vless: VlessSettings,
+    // Only read when a Shadowsocks inbound is configured
+    shadowsocks_cipher: shadowsocks::CipherType,
+}
+
+impl Protocol {
+    // As the control plane knows it in tariffs.protocols
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Vless => "vless",
+            Protocol::Vmess => "vmess",
+            Protocol::Trojan => "trojan",
+            Protocol::Shadowsocks => "shadowsocks",
+        }
+    }
+
+    // `vless_flow` overrides VLESS_FLOW for one inbound (VLESS_FLOWS)
+    fn account(self, uuid: &str, settings: &AccountSettings, vless_flow: Option<&str>) -> TypedMessage {
+        let vless = &settings.vless;
+        match self {
+            Protocol::Vless => typed_message(&vless::Account {
+                id: uuid.to_string(),
+                flow: vless_flow.unwrap_or(&vless.flow).to_string(),
+                encryption: vless.encryption.clone(),
+            }),
+            Protocol::Vmess => typed_message(&vmess::Account {
+                id: uuid.to_string(),
+                security_settings: None,
+                tests_enabled: String::new(),
+            }),
+            Protocol::Trojan => typed_message(&trojan::Account {
+                password: trojan_password(uuid),
+            }),
+            Protocol::Shadowsocks => typed_message(&shadowsocks::Account {
+                password: shadowsocks_password(uuid),
+                cipher_type: settings.shadowsocks_cipher as i32,
+                iv_check: false,
+            }),
+        }
+    }
+}
+
+// SS_METHOD as written in Xray's config. The 2022 ciphers belong to a different inbound type
+// (shadowsocks_2022) with server-side keys and aren't handled here.
+fn parse_shadowsocks_cipher(method: &str) -> Result<shadowsocks::CipherType> {
+    use shadowsocks::CipherType;
+    match method.to_ascii_lowercase().as_str() {
+        "aes-128-gcm" => Ok(CipherType::Aes128Gcm),
+        "aes-256-gcm" => Ok(CipherType::Aes256Gcm),
+        "chacha20-poly1305" | "chacha20-ietf-poly1305" => Ok(CipherType::Chacha20Poly1305),
+        "xchacha20-poly1305" | "xchacha20-ietf-poly1305" => Ok(CipherType::Xchacha20Poly1305),
+        "none" | "plain" => Ok(CipherType::None),
+        other => anyhow::bail!("unsupported SS_METHOD: {}", other),
+    }
+}
+
+// Trojan has no id field, so the subscription UUID doubles as the password.
+// Clients building trojan:// links must use the same value.
+fn trojan_password(uuid: &str) -> String {
+    uuid.to_string()
+}
+
+// Same for Shadowsocks: the dashed UUID is the password, so ss:// links need nothing extra.
+// Xray still tracks these users by email, which is what RemoveUser matches on.
+fn shadowsocks_password(uuid: &str) -> String {
+    uuid.to_string()
+}
+
+fn typed_message<M: Message + Name>(message: &M) -> TypedMessage {
+    TypedMessage {
+        r#type: M::type_url().trim_start_matches('/').to_string(),
+        value: message.encode_to_vec(),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Inbound {
+    tag: String,
+    protocol: Protocol,
+    // VLESS flow for this inbound when it differs from VLESS_FLOW
+    flow: Option<String>,
+}
+
+// "tag" or "tag:protocol"; the protocol defaults to VLESS
+fn parse_inbound(spec: &str) -> Result<Inbound> {
+    match spec.split_once(':') {
+        Some((tag, protocol)) => Ok(Inbound {
+            tag: tag.trim().to_string(),
+            protocol: protocol.trim().parse()?,
+            flow: None,
+        }),
+        None => Ok(Inbound {
+            tag: spec.to_string(),
+            protocol: Protocol::Vless,
+            flow: None,
+        }),
+    }
+}
+
+// VLESS_FLOWS="tag-reality:vision,tag-tls:none": per-inbound flows for agents serving both
+// Reality+Vision and plain TLS VLESS inbounds. "vision" and "none" are shorthands; full flow
+// names work too. Every tag must be a configured VLESS inbound.
+fn apply_vless_flows(inbounds: &mut [Inbound], spec: &str) -> Result<()> {
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (tag, flow) = entry
+            .split_once(':')
+            .with_context(|| format!("VLESS_FLOWS entry {:?} is not tag:flow", entry))?;
+        let (tag, flow) = (tag.trim(), flow.trim());
+        let flow = match flow {
+            "vision" => "xtls-rprx-vision",
+            "none" => "",
+            other => other,
+        };
+        let inbound = inbounds
+            .iter_mut()
+            .find(|i| i.tag == tag)
+            .with_context(|| format!("VLESS_FLOWS names {}, which is not in XRAY_INBOUND_TAG", tag))?;
+        anyhow::ensure!(
+            inbound.protocol == Protocol::Vless,
+            "VLESS_FLOWS names {}, which is a {:?} inbound",
+            tag,
+            inbound.protocol
+        );
+        if !KNOWN_VLESS_FLOWS.contains(&flow) {
+            warn!("VLESS_FLOWS flow {:?} for {} is not a known Xray flow, clients may fail to connect", flow, tag);
+        }
+        inbound.flow = Some(flow.to_string());
+    }
+    Ok(())
+}
+
+enum DeltaResult {
+    Changes(DeltaResponse),
+    // Control plane no longer accepts our cursor, a full sync is needed
+    CursorRejected,
+}
+
+// Where Xray's gRPC API lives and, for remote Xray hosts, how to reach it over TLS
+#[derive(Clone)]
+struct GrpcTarget {
+    addr: String,
+    tls: Option<ClientTlsConfig>,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    keepalive: Duration,
+}
+
+// Accepts http(s)://host:port or a bare host:port (taken as http://). Hosts may be DNS names,
+// IPv4 or bracketed IPv6 ("[::1]:8080"). Returns the scheme://host:port form tonic expects.
+fn normalize_grpc_addr(raw: &str) -> Result<String> {
+    let raw = raw.trim();
+    let with_scheme = if raw.contains("://") {
+        raw.to_string()
+    } else {
+        format!("http://{}", raw)
+    };
+    let uri: tonic::transport::Uri = with_scheme
+        .parse()
+        .map_err(|e| anyhow::anyhow!("not a valid address ({}); IPv6 hosts need brackets, e.g. http://[::1]:8080", e))?;
+
+    let scheme = uri.scheme_str().unwrap_or_default();
+    anyhow::ensure!(scheme == "http" || scheme == "https", "scheme must be http or https, got {}", scheme);
+    let host = uri.host().filter(|h| !h.is_empty()).context("missing host")?;
+    let port = uri.port_u16().context("missing port (Xray's api.listen port, e.g. :8080)")?;
+    anyhow::ensure!(
+        matches!(uri.path(), "" | "/") && uri.query().is_none(),
+        "must not have a path, got {}",
+        uri.path()
+    );
+    Ok(format!("{}://{}:{}", scheme, host, port))
 }
 
 struct XrayClient {
     client: HandlerServiceClient<Channel>,
-    inbound_tag: String,
+    target: GrpcTarget,
+    // Every user is provisioned on all of these inbounds
+    inbounds: Vec<Inbound>,
+    accounts: AccountSettings,
+    // Log alter_inbound calls instead of sending them
+    dry_run: bool,
+    // alter_inbound calls in a row that failed at the connection level
+    conn_failures: AtomicU32,
+    // Inbounds Xray currently doesn't have (e.g. mid config reload), skipped until they return.
+    // Refreshed once per sync cycle by check_inbounds.
+    missing_inbounds: Mutex<HashSet<String>>,
+}
+
+async fn connect_channel(target: &GrpcTarget) -> Result<Channel> {
+    let mut endpoint = Endpoint::from_shared(target.addr.clone())?
+        .connect_timeout(target.connect_timeout)
+        .timeout(target.request_timeout)
+        .tcp_keepalive(Some(target.keepalive))
+        .keep_alive_while_idle(true);
+    if let Some(tls) = &target.tls {
+        endpoint = endpoint.tls_config(tls.clone())?;
+    }
+
+    Ok(endpoint.connect().await?)
 }
 
 impl XrayClient {
-    async fn new(grpc_addr: &str, inbound_tag: Option<String>) -> Result<Self> {
-        let endpoint = Endpoint::from_shared(grpc_addr.to_string())?
-            .connect_timeout(Duration::from_secs(5))
-            .timeout(Duration::from_secs(5))
-            .tcp_keepalive(Some(Duration::from_secs(30)))
-            .keep_alive_while_idle(true);
-
-        let channel = endpoint.connect().await?;
+    async fn new(
+        target: &GrpcTarget,
+        inbounds: Vec<Inbound>,
+        accounts: AccountSettings,
+        dry_run: bool,
+    ) -> Result<Self> {
+        let channel = connect_channel(target).await?;
         let client = HandlerServiceClient::new(channel);
         Ok(Self {
             client,
-            inbound_tag: inbound_tag.unwrap_or_else(|| DEFAULT_INBOUND_TAG.to_string()),
+            target: target.clone(),
+            inbounds,
+            accounts,
+            dry_run,
+            conn_failures: AtomicU32::new(0),
+            missing_inbounds: Mutex::new(HashSet::new()),
         })
     }
 
-    async fn add_user(&mut self, user_cfg: &UserConfig) -> Result<()> {
-        let vless_account = Account {
-            id: user_cfg.uuid.clone(),
-            flow: "xtls-rprx-vision".to_string(),
-            encryption: "none".to_string(),
-            ..Default::default()
-        };
+    fn needs_reconnect(&self) -> bool {
+        self.conn_failures.load(Ordering::Relaxed) >= RECONNECT_AFTER_FAILURES
+    }
 
-        let account_type = Account::type_url();
-        let account_type_clean = account_type.trim_start_matches('/');
-        
-        let account_typed = TypedMessage {
-            r#type: account_type_clean.to_string(),
-            value: vless_account.encode_to_vec(),
-        };
+    // Probe every configured inbound so a wrong XRAY_INBOUND_TAG fails at startup instead of on
+    // every add. Xray has no call to list inbounds; GetInboundUsersCount reports unknown tags.
+    // Only missing tags are an error, anything else is logged and left to the sync loop.
+    async fn verify_inbounds(&self) -> Result<()> {
+        let mut client = self.client.clone();
+        let mut missing: Vec<String> = Vec::new();
+        for inbound in &self.inbounds {
+            let request = GetInboundUserRequest {
+                tag: inbound.tag.clone(),
+                email: String::new(),
+            };
+            match client.get_inbound_users_count(tonic::Request::new(request)).await {
+                Ok(res) => info!(
+                    "Inbound {} ({:?}) found with {} users",
+                    inbound.tag,
+                    inbound.protocol,
+                    res.into_inner().count
+                ),
+                Err(status) if is_handler_not_found(&status) => missing.push(inbound.tag.clone()),
+                // Xray builds before the users API can't tell us; not worth refusing to start over
+                Err(status) if status.code() == tonic::Code::Unimplemented => {
+                    warn!("Xray can't report inbound users, skipping inbound tag check");
+                    return Ok(());
+                }
+                Err(status) => warn!("Could not check inbound {} ({})", inbound.tag, describe_status(&status)),
+            }
+        }
+        anyhow::ensure!(
+            missing.is_empty(),
+            "inbound tag(s) not found in Xray: {}. XRAY_INBOUND_TAG must match inbounds[].tag in the Xray config",
+            missing.join(", ")
+        );
+        Ok(())
+    }
 
-        let user = User {
-            level: user_cfg.level, // Apply the Tariff Level Here
-            email: user_cfg.email.clone(),
-            account: Some(account_typed),
-        };
+    // Per-cycle probe for inbounds that went away at runtime (verify_inbounds covers startup).
+    // Logs each missing inbound once per cycle rather than once per user operation, and returns
+    // the tags of inbounds that were missing and are back: they lost their users, which need re-adding.
+    async fn check_inbounds(&self) -> Vec<String> {
+        let mut client = self.client.clone();
+        let mut missing = HashSet::new();
+        for inbound in &self.inbounds {
+            let request = GetInboundUserRequest {
+                tag: inbound.tag.clone(),
+                email: String::new(),
+            };
+            match client.get_inbound_users_count(tonic::Request::new(request)).await {
+                Err(status) if is_handler_not_found(&status) => {
+                    warn!("Inbound {} is missing from Xray, skipping it until it returns", inbound.tag);
+                    missing.insert(inbound.tag.clone());
+                }
+                // Unknown either way (old Xray, connection trouble); leave it to the calls themselves
+                _ => {}
+            }
+        }
+        let previous = std::mem::replace(&mut *self.missing_inbounds.lock().unwrap(), missing.clone());
+        let returned: Vec<String> = previous.difference(&missing).cloned().collect();
+        if !returned.is_empty() {
+            info!("Inbound(s) back in Xray: {:?}", returned);
+        }
+        returned
+    }
 
-        let op = AddUserOperation { user: Some(user) };
-        let op_type = AddUserOperation::type_url();
-        let op_type_clean = op_type.trim_start_matches('/');
+    fn is_inbound_missing(&self, tag: &str) -> bool {
+        self.missing_inbounds.lock().unwrap().contains(tag)
+    }
 
-        let operation = TypedMessage {
-            r#type: op_type_clean.to_string(),
-            value: op.encode_to_vec(),
+    // Emails Xray currently holds on `inbound`; None when this Xray build can't list users
+    async fn inbound_emails(&self, inbound: &Inbound) -> Result<Option<HashSet<String>>> {
+        let request = GetInboundUserRequest {
+            tag: inbound.tag.clone(),
+            email: String::new(),
         };
+        match self.client.clone().get_inbound_users(tonic::Request::new(request)).await {
+            Ok(res) => Ok(Some(res.into_inner().users.into_iter().map(|u| u.email).collect())),
+            Err(status) if status.code() == tonic::Code::Unimplemented => Ok(None),
+            Err(status) => anyhow::bail!("listing users on {} ({})", inbound.tag, describe_status(&status)),
+        }
+    }
 
-        let request = AlterInboundRequest {
-            tag: self.inbound_tag.clone(),
-            operation: Some(operation),
-        };
+    // Replace the channel, e.g. after Xray restarted underneath us
+    async fn reconnect(&mut self) -> Result<()> {
+        let channel = connect_channel(&self.target).await?;
+        self.client = HandlerServiceClient::new(channel);
+        self.conn_failures.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // One alter_inbound call, retried with backoff while Xray reports a transient failure.
+    // Errors matching `tolerated` mean the desired state already holds and count as success.
+    async fn alter_with_retry(
+        &self,
+        request: AlterInboundRequest,
+        tolerated: fn(&tonic::Status) -> bool,
+    ) -> Result<(), tonic::Status> {
+        if self.dry_run {
+            let op_type = request.operation.as_ref().map_or("", |op| op.r#type.as_str());
+            info!("[DRY RUN] would alter inbound {} with {}", request.tag, op_type);
+            return Ok(());
+        }
+
+        // Once the channel looks dead, fail fast until the main loop reconnects
+        if self.needs_reconnect() {
+            return Err(tonic::Status::unavailable("xray connection lost, awaiting reconnect"));
+        }
+
+        // Channels are cheap to clone and multiplex, which lets calls run concurrently
+        let mut client = self.client.clone();
+        let mut delays = ALTER_RETRY_DELAYS_SECS.iter();
+        loop {
+            let result = client.alter_inbound(tonic::Request::new(request.clone())).await;
+            match &result {
+                Err(status) if is_connection_error(status) => {
+                    self.conn_failures.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => self.conn_failures.store(0, Ordering::Relaxed),
+            }
+            match result {
+                Ok(_) => return Ok(()),
+                Err(status) if tolerated(&status) => return Ok(()),
+                Err(status) if self.needs_reconnect() => return Err(status),
+                Err(status) if is_transient(&status) => match delays.next() {
+                    Some(secs) => {
+                        warn!(
+                            code = ?status.code(),
+                            "alter_inbound on {} failed ({}), retrying in {}s",
+                            request.tag,
+                            describe_status(&status),
+                            secs
+                        );
+                        tokio::time::sleep(Duration::from_secs(*secs)).await;
+                    }
+                    None => return Err(status),
+                },
+                Err(status) => return Err(status),
+            }
+        }
+    }
 
-        self.client.alter_inbound(tonic::Request::new(request)).await?;
+    // Send an operation to every inbound `only` picks, built per inbound since accounts differ by
+    // protocol. Keeps going past failures and names the failed tags.
+    async fn alter_inbounds(
+        &self,
+        only: impl Fn(&Inbound) -> bool,
+        operation: impl Fn(&Inbound) -> TypedMessage,
+        tolerated: fn(&tonic::Status) -> bool,
+    ) -> Result<()> {
+        let mut failed: Vec<String> = Vec::new();
+        for inbound in self.inbounds.clone() {
+            if !only(&inbound) || self.is_inbound_missing(&inbound.tag) {
+                continue;
+            }
+            let request = AlterInboundRequest {
+                tag: inbound.tag.clone(),
+                operation: Some(operation(&inbound)),
+            };
+            if let Err(status) = self.alter_with_retry(request, tolerated).await {
+                // Vanished since this cycle's check: skip it from now on, and say so only once
+                if is_handler_not_found(&status) {
+                    if self.missing_inbounds.lock().unwrap().insert(inbound.tag.clone()) {
+                        warn!("Inbound {} is missing from Xray, skipping it until it returns", inbound.tag);
+                    }
+                    continue;
+                }
+                debug!(code = ?status.code(), metadata = ?status.metadata(), "alter_inbound on {} failed", inbound.tag);
+                failed.push(format!("{} ({})", inbound.tag, describe_status(&status)));
+            }
+        }
+        anyhow::ensure!(failed.is_empty(), "failed on inbound(s): {}", failed.join(", "));
         Ok(())
     }
 
-    async fn remove_user(&mut self, email: &str) -> Result<()> {
-        let op = RemoveUserOperation { email: email.to_string() };
-        let op_type = RemoveUserOperation::type_url();
-        let op_type_clean = op_type.trim_start_matches('/');
+    // On every inbound the user belongs on
+    async fn add_user(&self, user_cfg: &UserConfig) -> Result<()> {
+        self.add_user_on(user_cfg, |inbound| user_cfg.on_inbound(&inbound.tag)).await
+    }
 
-        let operation = TypedMessage {
-            r#type: op_type_clean.to_string(),
-            value: op.encode_to_vec(),
+    #[tracing::instrument(name = "add_user", skip_all, fields(uuid = %user_cfg.uuid, email = %user_cfg.email))]
+    async fn add_user_on(&self, user_cfg: &UserConfig, only: impl Fn(&Inbound) -> bool) -> Result<()> {
+        let accounts = &self.accounts;
+        let operation = |inbound: &Inbound| {
+            let account = inbound.protocol.account(&user_cfg.uuid, accounts, inbound.flow.as_deref());
+            if self.dry_run {
+                info!("[DRY RUN] {} account for {} is {}", inbound.tag, user_cfg.email, account.r#type);
+            }
+            let user = User {
+                level: user_cfg.level, // Apply the Tariff Level Here
+                email: user_cfg.email.clone(),
+                account: Some(account),
+            };
+            typed_message(&AddUserOperation { user: Some(user) })
         };
 
-        let request = AlterInboundRequest {
-            tag: self.inbound_tag.clone(),
-            operation: Some(operation),
-        };
+        // A user that is already there is exactly what we wanted
+        self.alter_inbounds(only, operation, is_user_already_exists).await
+    }
 
-        self.client.alter_inbound(tonic::Request::new(request)).await?;
-        Ok(())
+    // From every inbound, whatever the user's tags: removal also has to clear inbounds the user
+    // was taken off while a removal there failed
+    async fn remove_user(&self, email: &str) -> Result<()> {
+        self.remove_user_from(email, |_| true).await
     }
+
+    async fn remove_user_from(&self, email: &str, only: impl Fn(&Inbound) -> bool) -> Result<()> {
+        let operation = typed_message(&RemoveUserOperation { email: email.to_string() });
+
+        // Removing a user Xray doesn't have is a no-op as far as we're concerned
+        self.alter_inbounds(only, |_| operation.clone(), is_user_not_found).await
+    }
+}
+
+// What a failed Xray call means for us. Xray reports most failures with code Unknown and puts
+// the reason in the message, so both are looked at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum XrayFailure {
+    // A tag that names no inbound: "failed to get handler > handler not found: <tag>"
+    InboundNotFound,
+    // Duplicate AddUser: "User <email> already exists."
+    UserAlreadyExists,
+    // RemoveUser for an unknown email: "User <email> not found."
+    UserNotFound,
+    // The channel itself is broken (Xray down or restarted), not just this one call
+    Unavailable,
+    // Xray overloaded or slow to answer
+    Overloaded,
+    Other,
 }
 
-async fn fetch_sync(client: &reqwest::Client, base_url: &str, server_secret: &str) -> Result<Vec<UserConfig>> {
+fn classify_status(status: &tonic::Status) -> XrayFailure {
+    let message = status.message();
+    match status.code() {
+        // Checked first: "handler not found" must not pass for a missing user
+        _ if message.contains("handler not found") => XrayFailure::InboundNotFound,
+        tonic::Code::AlreadyExists => XrayFailure::UserAlreadyExists,
+        tonic::Code::NotFound => XrayFailure::UserNotFound,
+        tonic::Code::Unavailable => XrayFailure::Unavailable,
+        tonic::Code::DeadlineExceeded | tonic::Code::ResourceExhausted => XrayFailure::Overloaded,
+        _ if message.contains("already exists") => XrayFailure::UserAlreadyExists,
+        _ if message.contains("not found") => XrayFailure::UserNotFound,
+        tonic::Code::Unknown if message.contains("transport error") || message.contains("Service was not ready") => {
+            XrayFailure::Unavailable
+        }
+        _ => XrayFailure::Other,
+    }
+}
+
+// The status as it goes into logs and errors: our classification, the gRPC code, Xray's message
+fn describe_status(status: &tonic::Status) -> String {
+    format!("{:?}, code {:?}: {}", classify_status(status), status.code(), status.message())
+}
+
+fn is_user_already_exists(status: &tonic::Status) -> bool {
+    classify_status(status) == XrayFailure::UserAlreadyExists
+}
+
+fn is_user_not_found(status: &tonic::Status) -> bool {
+    classify_status(status) == XrayFailure::UserNotFound
+}
+
+fn is_handler_not_found(status: &tonic::Status) -> bool {
+    classify_status(status) == XrayFailure::InboundNotFound
+}
+
+fn is_connection_error(status: &tonic::Status) -> bool {
+    classify_status(status) == XrayFailure::Unavailable
+}
+
+// Failures worth retrying: Xray restarting, overloaded, or slow to answer
+fn is_transient(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded | tonic::Code::ResourceExhausted
+    )
+}
+
+// With SYNC_SIGNING_KEY set, only apply bodies whose X-Sync-Signature (hex HMAC-SHA256) checks
+// out. Fails closed: a missing signature is as bad as a wrong one.
+async fn verified_body(res: reqwest::Response, signing_key: Option<&[u8]>) -> Result<Vec<u8>> {
+    let signature = res
+        .headers()
+        .get("X-Sync-Signature")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let body = res.bytes().await?;
+    if let Some(key) = signing_key {
+        let signature = signature.ok_or_else(|| anyhow::anyhow!("sync response is not signed"))?;
+        let signature = hex::decode(signature).map_err(|_| anyhow::anyhow!("malformed sync signature"))?;
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(&body);
+        mac.verify_slice(&signature)
+            .map_err(|_| anyhow::anyhow!("sync signature mismatch, refusing to apply"))?;
+    }
+    Ok(body.to_vec())
+}
+
+// A 429 or 503 that said when to come back (Retry-After in seconds)
+#[derive(Debug)]
+struct Backpressure {
+    status: reqwest::StatusCode,
+    retry_after: Duration,
+}
+
+impl std::fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "control plane returned {}, retry after {}s", self.status, self.retry_after.as_secs())
+    }
+}
+
+impl std::error::Error for Backpressure {}
+
+// Fails with Backpressure when the control plane asks us to back off. Retry-After given as an
+// HTTP date is ignored; ours and common proxies send seconds.
+fn check_backpressure(res: &reqwest::Response) -> Result<()> {
+    let status = res.status();
+    if status != reqwest::StatusCode::TOO_MANY_REQUESTS && status != reqwest::StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(());
+    }
+    let secs = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    match secs {
+        Some(secs) => Err(Backpressure {
+            status,
+            retry_after: Duration::from_secs(secs.min(MAX_RETRY_AFTER_SECS)),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+// How long a failed request asked us to wait before the next attempt, if it did
+fn retry_after_hint(e: &anyhow::Error) -> Option<Duration> {
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<Backpressure>())
+        .map(|b| b.retry_after)
+}
+
+// The control plane tags every request with X-Request-Id; logging it lets both sides' logs be joined
+fn request_id(res: &reqwest::Response) -> String {
+    res.headers()
+        .get("X-Request-Id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string()
+}
+
+// Coarse cause of a failed sync, so logs tell a down control plane from a broken response
+fn sync_error_kind(e: &anyhow::Error) -> &'static str {
+    if retry_after_hint(e).is_some() {
+        "control plane busy"
+    } else if e.chain().any(|cause| cause.is::<serde_json::Error>()) {
+        "invalid JSON"
+    } else if e.chain().any(|cause| cause.is::<reqwest::Error>()) {
+        "transport error"
+    } else {
+        "bad status or signature"
+    }
+}
+
+// Runs `request` against each control plane replica in order and returns the first success,
+// or the last replica's error. Replicas share one database, so any of them can serve a sync and
+// delta cursors carry over between them.
+async fn first_success<'a, T, F, Fut>(urls: &'a [String], what: &str, mut request: F) -> Result<T>
+where
+    F: FnMut(&'a str) -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut last_err = None;
+    for (i, url) in urls.iter().enumerate() {
+        match request(url).await {
+            Ok(value) => {
+                if i == 0 {
+                    debug!("{} served by {}", what, url);
+                } else {
+                    info!("{} served by fallback control plane {}", what, url);
+                }
+                return Ok(value);
+            }
+            Err(e) => {
+                if i + 1 < urls.len() {
+                    warn!("{} via {} failed, trying next control plane: {:#}", what, url, e);
+                }
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.expect("at least one control plane URL"))
+}
+
+// Full sync over /sync/stream (SYNC_STREAM=true), for servers whose user list is too big to
+// comfortably build and send as one JSON document. Lines are parsed as they arrive; nothing is
+// returned unless the trailer came through, so a cut-off stream never looks like a short list.
+async fn fetch_sync_stream(
+    client: &reqwest::Client,
+    base_url: &str,
+    server_secret: &str,
+    signing_key: Option<&[u8]>,
+) -> Result<SyncResponse> {
+    let url = format!("{}/api/internal/sync/stream", base_url.trim_end_matches('/'));
+    debug!("Streaming sync from Control Plane at {}", url);
+    let sent_at = Utc::now();
+    let mut res = client
+        .get(&url)
+        .header("X-Server-Secret", server_secret)
+        .query(&[("detailed", "true")])
+        .send()
+        .await?;
+    let request_id = request_id(&res);
+    check_backpressure(&res)?;
+    anyhow::ensure!(
+        res.status().is_success(),
+        "sync stream returned {} (request id {})",
+        res.status(),
+        request_id
+    );
+
+    let mut mac = signing_key.map(|key| Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length"));
+    let mut users = Vec::new();
+    let mut trailer: Option<StreamTrailer> = None;
+    let mut signature: Option<String> = None;
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        let chunk = res
+            .chunk()
+            .await
+            .with_context(|| format!("sync stream request id {}", request_id))?;
+        let at_eof = chunk.is_none();
+        if let Some(chunk) = chunk {
+            buf.extend_from_slice(&chunk);
+        }
+        // Complete lines only; a partial one waits for the next chunk
+        let mut consumed = 0;
+        while let Some(len) = buf[consumed..].iter().position(|b| *b == b'\n') {
+            let line = &buf[consumed..consumed + len + 1];
+            consumed += len + 1;
+            anyhow::ensure!(signature.is_none(), "sync stream has data after its signature");
+            match serde_json::from_slice(line)? {
+                StreamLine::Signature { signature: sig } => {
+                    anyhow::ensure!(trailer.is_some(), "sync stream signature before its trailer");
+                    signature = Some(sig);
+                    continue;
+                }
+                _ if trailer.is_some() => anyhow::bail!("sync stream has data after its trailer"),
+                StreamLine::User(user) => users.push(user),
+                StreamLine::End { end } => trailer = Some(end),
+            }
+            if let Some(mac) = &mut mac {
+                mac.update(line);
+            }
+        }
+        buf.drain(..consumed);
+        if at_eof {
+            break;
+        }
+    }
+
+    let trailer = trailer.ok_or_else(|| anyhow::anyhow!("sync stream ended without a trailer (request id {})", request_id))?;
+    anyhow::ensure!(
+        trailer.count == users.len(),
+        "sync stream announced {} users but sent {}",
+        trailer.count,
+        users.len()
+    );
+    if let Some(mac) = mac {
+        let signature = signature.ok_or_else(|| anyhow::anyhow!("sync stream is not signed"))?;
+        let signature = hex::decode(signature).map_err(|_| anyhow::anyhow!("malformed sync signature"))?;
+        mac.verify_slice(&signature)
+            .map_err(|_| anyhow::anyhow!("sync signature mismatch, refusing to apply"))?;
+    }
+
+    check_sync_version(trailer.version, "sync stream")?;
+    check_clock_skew(trailer.server_time, sent_at);
+    normalize_uuids(&mut users, "sync stream");
+    default_emails(&mut users);
+    dedup_users(&mut users, "sync stream");
+    debug!(request_id = %request_id, "Sync stream returned {} users", users.len());
+    Ok(SyncResponse {
+        version: trailer.version,
+        users,
+        cursor: trailer.cursor,
+        server_time: trailer.server_time,
+        resync: trailer.resync,
+        etag: None,
+    })
+}
+
+// Full sync by whichever endpoint SYNC_STREAM picks. The stream has no ETag support.
+async fn fetch_full(
+    client: &reqwest::Client,
+    base_url: &str,
+    server_secret: &str,
+    etag: Option<&str>,
+    signing_key: Option<&[u8]>,
+    stream: bool,
+) -> Result<SyncResult> {
+    if stream {
+        fetch_sync_stream(client, base_url, server_secret, signing_key)
+            .await
+            .map(SyncResult::Full)
+    } else {
+        fetch_sync(client, base_url, server_secret, etag, signing_key).await
+    }
+}
+
+// v1 and v2 read the same, v2 just names its version. A newer format than we asked for means
+// the control plane ignored Accept-Version; applying it could misread users, so it's refused.
+fn check_sync_version(version: Option<u32>, what: &str) -> Result<()> {
+    match version.unwrap_or(1) {
+        1 | 2 => Ok(()),
+        v => anyhow::bail!(
+            "{} is in format v{}, this agent understands up to v{}; upgrade the agent",
+            what,
+            v,
+            SYNC_FORMAT_VERSION
+        ),
+    }
+}
+
+// Our clock minus the control plane's, judged by the server_time it stamped somewhere between
+// sending the request and now; anything within that window counts as no skew. Only logged and
+// exported: expire_date cutoffs still go by our clock.
+fn check_clock_skew(server_time: Option<DateTime<Utc>>, sent_at: DateTime<Utc>) {
+    let Some(server_time) = server_time else {
+        return;
+    };
+    let received_at = Utc::now();
+    let skew = if server_time < sent_at {
+        sent_at - server_time
+    } else if server_time > received_at {
+        received_at - server_time
+    } else {
+        chrono::Duration::zero()
+    };
+    metrics::gauge!("clock_skew_seconds").set(skew.num_milliseconds() as f64 / 1000.0);
+    if skew.num_seconds().abs() > CLOCK_SKEW_WARN_SECS {
+        warn!(
+            "Clock is {:.1}s {} the control plane's (server time {}); check NTP, expiry removals are off by as much",
+            skew.num_milliseconds().abs() as f64 / 1000.0,
+            if skew > chrono::Duration::zero() { "ahead of" } else { "behind" },
+            server_time
+        );
+    } else {
+        debug!("Clock skew to the control plane: {}ms", skew.num_milliseconds());
+    }
+}
+
+// Drop users whose UUID doesn't parse, which Xray would only reject with an opaque error (or
+// worse, accept as a password), and write the rest in lowercase hyphenated form so the same user
+// always compares equal however the control plane cased it
+fn normalize_uuids(users: &mut Vec<UserConfig>, what: &str) {
+    users.retain_mut(|u| match uuid::Uuid::parse_str(u.uuid.trim()) {
+        Ok(id) => {
+            u.uuid = id.hyphenated().to_string();
+            true
+        }
+        Err(e) => {
+            warn!("{} returned user {:?} with invalid UUID {:?} ({}), skipping it", what, u.email, u.uuid, e);
+            false
+        }
+    });
+}
+
+// Falls back to UUID-as-email for users synced without an email
+fn default_emails(users: &mut [UserConfig]) {
+    for user in users.iter_mut().filter(|u| u.email.is_empty()) {
+        user.email = user.uuid.clone();
+    }
+}
+
+// Keep the first entry per UUID and per email. Duplicates point at bad data on the control plane;
+// applied as-is, two concurrent adds would race on the same Xray user.
+fn dedup_users(users: &mut Vec<UserConfig>, what: &str) {
+    let before = users.len();
+    let mut uuids = HashSet::new();
+    let mut emails = HashSet::new();
+    users.retain(|u| {
+        let fresh = !uuids.contains(&u.uuid) && !emails.contains(&u.email);
+        if fresh {
+            uuids.insert(u.uuid.clone());
+            emails.insert(u.email.clone());
+        }
+        fresh
+    });
+    if users.len() < before {
+        warn!("{} returned {} duplicate users, ignoring them", what, before - users.len());
+    }
+}
+
+async fn fetch_sync(
+    client: &reqwest::Client,
+    base_url: &str,
+    server_secret: &str,
+    etag: Option<&str>,
+    signing_key: Option<&[u8]>,
+) -> Result<SyncResult> {
     let url = format!("{}/api/internal/sync", base_url.trim_end_matches('/'));
-    println!("Fetching sync from Control Plane at {}", url);
-    let res = client.get(&url).header("X-Server-Secret", server_secret).send().await?;
-    anyhow::ensure!(res.status().is_success(), "sync returned {}", res.status());
-    let body: SyncResponse = res.json().await?;
-    Ok(body.users)
+    debug!("Fetching sync from Control Plane at {}", url);
+    let mut req = client
+        .get(&url)
+        .header("X-Server-Secret", server_secret)
+        .query(&[("detailed", "true")]);
+    if let Some(etag) = etag {
+        req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    let sent_at = Utc::now();
+    let res = req.send().await?;
+    let request_id = request_id(&res);
+    if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+        anyhow::ensure!(etag.is_some(), "sync returned 304 without If-None-Match (request id {})", request_id);
+        debug!(request_id = %request_id, "Sync unchanged");
+        let cursor = res
+            .headers()
+            .get("X-Sync-Cursor")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned);
+        return Ok(SyncResult::Unchanged { cursor });
+    }
+    let response_etag = res
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    check_backpressure(&res)?;
+    anyhow::ensure!(
+        res.status().is_success(),
+        "sync returned {} (request id {})",
+        res.status(),
+        request_id
+    );
+    let body = verified_body(res, signing_key)
+        .await
+        .with_context(|| format!("sync request id {}", request_id))?;
+    let mut body: SyncResponse = serde_json::from_slice(&body)?;
+    check_sync_version(body.version, "sync")?;
+    check_clock_skew(body.server_time, sent_at);
+    body.etag = response_etag;
+    normalize_uuids(&mut body.users, "sync");
+    default_emails(&mut body.users);
+    dedup_users(&mut body.users, "sync");
+    debug!(request_id = %request_id, "Sync returned {} users", body.users.len());
+    Ok(SyncResult::Full(body))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let control_plane_url = std::env::var("CONTROL_PLANE_URL").expect("CONTROL_PLANE_URL set");
-    let server_secret = std::env::var("SERVER_SECRET").expect("SERVER_SECRET set");
-    let grpc_addr = std::env::var("XRAY_GRPC_ADDR").unwrap_or_else(|_| "http://127.0.0.1:8080".into());
-    let inbound_tag = std::env::var("XRAY_INBOUND_TAG").ok();
+async fn fetch_delta(
+    client: &reqwest::Client,
+    base_url: &str,
+    server_secret: &str,
+    since: &str,
+    signing_key: Option<&[u8]>,
+) -> Result<DeltaResult> {
+    let url = format!("{}/api/internal/sync/delta", base_url.trim_end_matches('/'));
+    let sent_at = Utc::now();
+    let res = client
+        .get(&url)
+        .header("X-Server-Secret", server_secret)
+        .query(&[("since", since), ("detailed", "true")])
+        .send()
+        .await?;
+    let request_id = request_id(&res);
+    // 404 means the control plane predates the delta route
+    if matches!(
+        res.status(),
+        reqwest::StatusCode::GONE | reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::NOT_FOUND
+    ) {
+        return Ok(DeltaResult::CursorRejected);
+    }
+    check_backpressure(&res)?;
+    anyhow::ensure!(
+        res.status().is_success(),
+        "sync delta returned {} (request id {})",
+        res.status(),
+        request_id
+    );
+    let body = verified_body(res, signing_key)
+        .await
+        .with_context(|| format!("sync delta request id {}", request_id))?;
+    let mut body: DeltaResponse = serde_json::from_slice(&body)?;
+    check_sync_version(body.version, "sync delta")?;
+    check_clock_skew(body.server_time, sent_at);
+    normalize_uuids(&mut body.added, "sync delta");
+    default_emails(&mut body.added);
+    dedup_users(&mut body.added, "sync delta");
+    debug!(
+        request_id = %request_id,
+        "Sync delta returned {} added, {} removed",
+        body.added.len(),
+        body.removed.len()
+    );
+    Ok(DeltaResult::Changes(body))
+}
+
+// Returns how many users were newly added
+async fn add_missing(
+    xray: &XrayInstances,
+    local_users: &mut HashMap<String, UserConfig>,
+    users: Vec<UserConfig>,
+) -> usize {
+    // Optional: Check if level changed and update
+    // else if local_users[&cfg.email].level != cfg.level { ... }
+    let mut missing: Vec<UserConfig> = Vec::new();
+    // Provisioned on one Xray instance but now routed to another (the tariff's target changed)
+    let mut moved: Vec<String> = Vec::new();
+    // Same instance, but belonging on other inbounds than before (the tariff's inbound_tags changed)
+    let mut retagged: Vec<UserConfig> = Vec::new();
+    for cfg in users {
+        match local_users.get_mut(&cfg.email) {
+            Some(local) if xray.index(local.target.as_deref()) != xray.index(cfg.target.as_deref()) => {
+                moved.push(cfg.email.clone());
+                missing.push(cfg);
+            }
+            Some(local) if xray.inbounds_changed(local, &cfg) => retagged.push(cfg),
+            // Already provisioned, but an extension moves the expiry we schedule removal on
+            Some(local) => local.expire_date = cfg.expire_date,
+            None => missing.push(cfg),
+        }
+    }
+    if !moved.is_empty() {
+        info!("{} users moving to another Xray instance", moved.len());
+        remove_present(xray, local_users, moved).await;
+        // Ones whose removal failed stay where they are until the next sync
+        missing.retain(|cfg| !local_users.contains_key(&cfg.email));
+    }
+    // One at a time: tariffs rarely change their inbounds, and then only for a few users at once
+    for cfg in retagged {
+        let Some(local) = local_users.get_mut(&cfg.email) else { continue };
+        info!("Moving user {} to inbounds {:?}", cfg.email, cfg.tags);
+        match xray.move_inbounds(local, &cfg).await {
+            Ok(()) => {
+                local.tags = cfg.tags;
+                local.expire_date = cfg.expire_date;
+            }
+            // The old tags stay recorded, so the next sync tries again
+            Err(e) => {
+                metrics::counter!("add_errors_total").increment(1);
+                error!("Failed to move user {} between inbounds: {}", cfg.email, e);
+            }
+        }
+    }
 
-    println!("Starting Proxy Agent for Server...");
+    let results: Vec<(UserConfig, Result<()>)> = stream::iter(missing)
+        .map(|cfg| async move {
+            info!("Adding user: {} [Level {}]", cfg.email, cfg.level);
+            let result = xray.add_user(&cfg).await;
+            (cfg, result)
+        })
+        .buffer_unordered(xray.concurrency)
+        .collect()
+        .await;
 
-    // 1. Establish initial Xray connection
-    let mut xray = loop {
-        match XrayClient::new(&grpc_addr, inbound_tag.clone()).await {
-            Ok(c) => break c,
-            Err(_) => {
-                eprintln!("Failed to connect to Xray at {}. Retrying in {} seconds...", grpc_addr, XRAY_CONNECT_RETRY_SECS);
-                tokio::time::sleep(Duration::from_secs(XRAY_CONNECT_RETRY_SECS)).await;
+    let mut added = 0;
+    for (cfg, result) in results {
+        match result {
+            Ok(()) => {
+                metrics::counter!("users_added_total").increment(1);
+                local_users.insert(cfg.email.clone(), cfg);
+                added += 1;
+            }
+            Err(e) => {
+                metrics::counter!("add_errors_total").increment(1);
+                error!("Failed to add user {}: {}", cfg.email, e);
             }
         }
+    }
+    added
+}
+
+// Returns how many users were actually removed. Each result is applied as soon as its call
+// finishes, so a caller that gives up half way (the shutdown purge's timeout) still leaves
+// local_users holding exactly the users not yet removed.
+async fn remove_present(
+    xray: &XrayInstances,
+    local_users: &mut HashMap<String, UserConfig>,
+    emails: Vec<String>,
+) -> usize {
+    let present: Vec<(String, String, Option<String>)> = emails
+        .into_iter()
+        .filter_map(|email| {
+            local_users
+                .get(&email)
+                .map(|cfg| (email, cfg.uuid.clone(), cfg.target.clone()))
+        })
+        .collect();
+
+    let mut results = stream::iter(present)
+        .map(|(email, uuid, target)| {
+            let span = tracing::info_span!("remove_user", uuid = %uuid, email = %email);
+            async move {
+                info!("Removing user: {}", email);
+                let result = xray.remove_user(&email, target.as_deref()).await;
+                (email, result)
+            }
+            .instrument(span)
+        })
+        .buffer_unordered(xray.concurrency);
+
+    let mut removed = 0;
+    while let Some((email, result)) = results.next().await {
+        match result {
+            Ok(()) => {
+                metrics::counter!("users_removed_total").increment(1);
+                local_users.remove(&email);
+                removed += 1;
+            }
+            Err(e) => {
+                metrics::counter!("remove_errors_total").increment(1);
+                error!("Failed to remove {}: {}", email, e);
+            }
+        }
+    }
+    removed
+}
+
+// Catch users removed from Xray behind our back (by hand, or an inbound reloaded on its own):
+// anything we think is provisioned but some inbound it belongs on lacks gets added again
+async fn verify_present(xray: &XrayInstances, local_users: &HashMap<String, UserConfig>) {
+    let mut lost: HashSet<String> = HashSet::new();
+    for (i, instance) in xray.instances.iter().enumerate() {
+        let client = &instance.client;
+        let expected: Vec<&UserConfig> = local_users
+            .values()
+            .filter(|cfg| xray.index(cfg.target.as_deref()) == Some(i))
+            .collect();
+        for inbound in client.inbounds.iter().filter(|i| !client.is_inbound_missing(&i.tag)) {
+            match client.inbound_emails(inbound).await {
+                Ok(Some(present)) => lost.extend(
+                    expected
+                        .iter()
+                        .filter(|cfg| cfg.on_inbound(&inbound.tag) && !present.contains(&cfg.email))
+                        .map(|cfg| cfg.email.clone()),
+                ),
+                Ok(None) => {
+                    warn!("Xray {} can't list inbound users, skipping verify pass", instance.name);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Verify pass on Xray {} failed: {:#}", instance.name, e);
+                    return;
+                }
+            }
+        }
+    }
+    if lost.is_empty() {
+        debug!("Verify pass: all {} users present in Xray", local_users.len());
+        return;
+    }
+
+    warn!("{} users missing from Xray, re-adding", lost.len());
+    metrics::counter!("verify_readded_total").increment(lost.len() as u64);
+    // add_user covers every inbound the user belongs on and tolerates the ones where the user is still there.
+    // Users stay in local_users either way, so a failed re-add is retried on the next pass.
+    for email in &lost {
+        let Some(cfg) = local_users.get(email) else { continue };
+        info!("Re-adding user: {}", email);
+        if let Err(e) = xray.add_user(cfg).await {
+            metrics::counter!("add_errors_total").increment(1);
+            error!("Failed to re-add user {}: {}", email, e);
+        }
+    }
+}
+
+// Add known users again on the inbounds `only` picks (by instance index and inbound), e.g. ones
+// that came back empty after a reload. Users already there are tolerated. local_users is left as
+// it is either way, so a user revoked meanwhile is still removed by the next sync and a failed
+// re-add is retried by the next verify pass.
+async fn readd_users(
+    xray: &XrayInstances,
+    local_users: &HashMap<String, UserConfig>,
+    only: impl Fn(usize, &Inbound) -> bool,
+) {
+    let only = &only;
+    let results: Vec<bool> = stream::iter(local_users.values())
+        .filter_map(|cfg| async move {
+            let i = xray.index(cfg.target.as_deref())?;
+            let picks = |inbound: &Inbound| cfg.on_inbound(&inbound.tag) && only(i, inbound);
+            xray.instances[i].client.inbounds.iter().any(picks).then_some((i, cfg))
+        })
+        .map(|(i, cfg)| async move {
+            let result = xray.instances[i]
+                .client
+                .add_user_on(cfg, |inbound| cfg.on_inbound(&inbound.tag) && only(i, inbound))
+                .await;
+            if let Err(e) = &result {
+                metrics::counter!("add_errors_total").increment(1);
+                error!("Failed to re-add user {}: {}", cfg.email, e);
+            }
+            result.is_ok()
+        })
+        .buffer_unordered(xray.concurrency)
+        .collect()
+        .await;
+    let failed = results.iter().filter(|ok| !**ok).count();
+    info!("Re-added {} users, {} failed", results.len() - failed, failed);
+}
+
+// Drop users whose subscription ran out without waiting for the next sync to report it
+async fn remove_expired(xray: &XrayInstances, local_users: &mut HashMap<String, UserConfig>) {
+    let now = Utc::now();
+    let expired: Vec<String> = local_users
+        .values()
+        .filter(|cfg| cfg.expire_date.is_some_and(|at| at <= now))
+        .map(|cfg| cfg.email.clone())
+        .collect();
+    if !expired.is_empty() {
+        info!("{} users reached their expiry", expired.len());
+        remove_present(xray, local_users, expired).await;
+    }
+}
+
+// Earliest upcoming expiry among provisioned users. Past ones are left out: if their removal
+// failed, the next sync reports them again rather than us spinning on them.
+fn next_expiry(local_users: &HashMap<String, UserConfig>) -> Option<DateTime<Utc>> {
+    let now = Utc::now();
+    local_users
+        .values()
+        .filter_map(|cfg| cfg.expire_date)
+        .filter(|at| *at > now)
+        .min()
+}
+
+// Full reconciliation: drop everything local the remote no longer has, add everything remote we lack.
+// Removals go first, as in the delta path: when a subscription is replaced within one cycle (same
+// UUID under a new email, e.g. a plan change), the old entry is gone before the new one is added
+// rather than both sharing the UUID on the inbound for a moment. The cost is that a replaced user
+// is briefly absent instead of briefly present twice. Returns (added, removed).
+async fn apply_full(
+    xray: &XrayInstances,
+    local_users: &mut HashMap<String, UserConfig>,
+    remote_users_list: Vec<UserConfig>,
+) -> (usize, usize) {
+    let remote_emails: HashSet<String> = remote_users_list.iter().map(|u| u.email.clone()).collect();
+
+    // 1. Process Removals
+    let stale: Vec<String> = local_users
+        .keys()
+        .filter(|email| !remote_emails.contains(*email))
+        .cloned()
+        .collect();
+    let removed = remove_present(xray, local_users, stale).await;
+
+    // 2. Process Additions / Updates
+    let added = add_missing(xray, local_users, remote_users_list).await;
+    (added, removed)
+}
+
+// ETag of a full sync, kept only if applying it left local_users holding exactly its users.
+// Otherwise a later 304 would paper over an add or removal that failed.
+fn etag_if_applied(
+    etag: Option<String>,
+    local_users: &HashMap<String, UserConfig>,
+    remote_emails: &HashSet<String>,
+) -> Option<String> {
+    etag.filter(|_| local_users.len() == remote_emails.len() && remote_emails.iter().all(|e| local_users.contains_key(e)))
+}
+
+// Users we provisioned before a restart. A missing or unreadable file just means starting empty.
+fn load_state(path: &Path) -> HashMap<String, UserConfig> {
+    match std::fs::read(path) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(users) => users,
+            Err(e) => {
+                warn!("Ignoring corrupt state file {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => {
+            error!("Failed to read state file {}: {}", path.display(), e);
+            HashMap::new()
+        }
+    }
+}
+
+// Write to a sibling temp file and rename over the target so a crash never leaves a torn file
+fn save_state(path: &Path, users: &HashMap<String, UserConfig>) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, serde_json::to_vec(users)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Sleep until the next sync, waking up in between to remove users as they expire.
+// Membership is still reconciled by every sync, which catches cancellations and bans.
+async fn wait_for_next_sync(
+    xray: &XrayInstances,
+    local_users: &mut HashMap<String, UserConfig>,
+    status_tx: &tokio::sync::watch::Sender<heartbeat::AgentStatus>,
+    interval: Duration,
+) {
+    let next_sync = tokio::time::Instant::now() + interval;
+    loop {
+        let wake_at = match next_expiry(local_users) {
+            Some(at) => {
+                let until = (at - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+                next_sync.min(tokio::time::Instant::now() + until)
+            }
+            None => next_sync,
+        };
+        tokio::time::sleep_until(wake_at).await;
+        if wake_at >= next_sync {
+            break;
+        }
+        remove_expired(xray, local_users).await;
+        status_tx.send_modify(|status| status.active_count = local_users.len());
+    }
+}
+
+// Base interval shifted by a fresh random offset of up to ±jitter_pct percent on every call
+fn jittered_interval(base: Duration, jitter_pct: u32) -> Duration {
+    if jitter_pct == 0 {
+        return base;
+    }
+    let factor = rand::thread_rng().gen_range(-(jitter_pct as f64)..=jitter_pct as f64) / 100.0;
+    base.mul_f64(1.0 + factor)
+}
+
+// One stats channel per Xray instance; each counts only the users it carries
+async fn stats_clients(targets: &[(String, GrpcTarget)]) -> Result<Vec<stats::StatsClient>> {
+    let mut clients = Vec::new();
+    for (name, target) in targets {
+        let channel = connect_channel(target)
+            .await
+            .with_context(|| format!("Xray {}", name))?;
+        clients.push(stats::StatsClient::new(channel));
+    }
+    Ok(clients)
+}
+
+// Resolves on the first SIGINT (Ctrl+C) or SIGTERM (docker stop / systemd)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
     };
-    
-    println!("Connected to Xray at {}", grpc_addr);
 
-    let http_client = reqwest::Client::new();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// PURGE_ON_SHUTDOWN: take every user we provisioned off Xray so a stopped agent doesn't leave
+// them connected. Whatever isn't removed within `timeout` stays in Xray and in the state file,
+// so the next start still removes it if the control plane no longer lists it.
+async fn purge_users(
+    xray: &XrayInstances,
+    local_users: &mut HashMap<String, UserConfig>,
+    state_file: Option<&Path>,
+    timeout: Duration,
+) {
+    let total = local_users.len();
+    info!("Purging {} users from Xray before exiting", total);
+    let emails: Vec<String> = local_users.keys().cloned().collect();
+    match tokio::time::timeout(timeout, remove_present(xray, local_users, emails)).await {
+        Ok(removed) if removed == total => info!("Purged all {} users", removed),
+        Ok(removed) => warn!("Purged {} of {} users, the rest failed to remove", removed, total),
+        Err(_) => warn!("Purge did not finish within {:?}, some users stay in Xray", timeout),
+    }
+    if let Some(path) = state_file {
+        if let Err(e) = save_state(path, local_users) {
+            error!("Failed to write state file {}: {}", path.display(), e);
+        }
+    }
+}
+
+// LOG_FORMAT=json switches to one JSON object per line for log shippers; RUST_LOG sets the level
+fn init_tracing() {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if std::env::var("LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json")) {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    if std::env::args().skip(1).any(|a| a == "--version" || a == "-V") {
+        println!("proxy_agent {} ({})", VERSION, GIT_SHA);
+        return Ok(());
+    }
+    init_tracing();
+    info!("Starting Proxy Agent {} ({})", VERSION, GIT_SHA);
+    let config = config::Config::from_env()?;
+    config.log();
+    let config::Config {
+        control_plane_urls,
+        server_secret,
+        sync_signing_key,
+        xray_targets,
+        state_file,
+        inbounds,
+        accounts,
+        sync_stream,
+        dry_run,
+        xray_concurrency,
+        xray_connect_max_wait,
+        sync_interval,
+        sync_jitter_pct,
+        usage_interval_secs,
+        online_interval_secs,
+        heartbeat_interval_secs,
+        verify_interval_secs,
+        sync_failure_alert,
+        sync_exit_after,
+        purge_on_shutdown,
+        purge_timeout,
+        http_connect_timeout,
+        http_timeout,
+        health_addr,
+        status_token,
+    } = config;
+    let signing_key = sync_signing_key.as_deref();
+
+    // Only logged: in compose setups the name may not resolve until the Xray container is up
+    for (_, target) in &xray_targets {
+        let grpc_addr = &target.addr;
+        let grpc_authority = grpc_addr.split_once("://").map_or(grpc_addr.as_str(), |(_, a)| a);
+        match tokio::net::lookup_host(grpc_authority).await {
+            Ok(resolved) => info!(
+                "Xray gRPC endpoint {} ({}) resolves to {:?}",
+                grpc_addr,
+                if target.tls.is_some() { "TLS" } else { "plaintext" },
+                resolved.collect::<Vec<_>>()
+            ),
+            Err(e) => warn!("Xray gRPC endpoint {} does not resolve yet: {}", grpc_addr, e),
+        }
+    }
+
+    // 1. Establish initial Xray connection(s)
+    let mut xray = XrayInstances::connect(
+        &xray_targets,
+        inbounds.clone(),
+        accounts.clone(),
+        xray_concurrency,
+        dry_run,
+        xray_connect_max_wait,
+    )
+    .await?;
+    let grpc_addr = xray.describe();
+    xray.verify_inbounds().await?;
+    if dry_run {
+        warn!("[DRY RUN] Xray will not be modified; usage and online reports, heartbeats and the state file are off");
+    }
+
+    // Declared on every request so sync (and the heartbeat's expected count) skips users on
+    // tariffs none of our inbounds can carry
+    let mut protocols: Vec<&str> = inbounds.iter().map(|i| i.protocol.name()).collect();
+    protocols.sort_unstable();
+    protocols.dedup();
+    let mut default_headers = reqwest::header::HeaderMap::new();
+    default_headers.insert("x-agent-protocols", reqwest::header::HeaderValue::from_str(&protocols.join(","))?);
+    default_headers.insert("accept-version", reqwest::header::HeaderValue::from(SYNC_FORMAT_VERSION));
+    let http_client = reqwest::Client::builder()
+        .default_headers(default_headers)
+        .connect_timeout(http_connect_timeout)
+        .timeout(http_timeout)
+        .build()?;
+
+    // Stats are read with reset, which would steal counts from a real agent on the same Xray
+    if usage_interval_secs > 0 && !dry_run {
+        match stats_clients(&xray_targets).await {
+            Ok(clients) => {
+                tokio::spawn(stats::report_usage_loop(
+                    clients,
+                    http_client.clone(),
+                    control_plane_urls.clone(),
+                    server_secret.clone(),
+                    Duration::from_secs(usage_interval_secs),
+                ));
+            }
+            Err(e) => warn!("Usage reporting disabled, could not open stats channel: {}", e),
+        }
+    }
+    if online_interval_secs > 0 && !dry_run {
+        match stats_clients(&xray_targets).await {
+            Ok(clients) => {
+                tokio::spawn(stats::report_online_loop(
+                    clients,
+                    http_client.clone(),
+                    control_plane_urls.clone(),
+                    server_secret.clone(),
+                    Duration::from_secs(online_interval_secs),
+                ));
+            }
+            Err(e) => warn!("Online reporting disabled, could not open stats channel: {}", e),
+        }
+    }
     
     // Track active users by Email (unique identifier in Xray)
     // We store the whole config to check if level changed later (optional optimization)
-    let mut local_users: HashMap<String, UserConfig> = HashMap::new();
+    // Seeded from STATE_FILE when set; the initial full sync drops stale entries
+    // Only this task touches it (and Xray's user lists): syncs, expiry removals and verify passes
+    // run one after another in the loop below, and the next sync is timed from the end of the
+    // previous one, so a slow cycle delays the next instead of overlapping it. Keep it that way
+    // rather than moving reconciliation into spawned tasks.
+    let mut local_users: HashMap<String, UserConfig> = match &state_file {
+        Some(path) => load_state(path),
+        None => HashMap::new(),
+    };
+    if !local_users.is_empty() {
+        info!("Restored {} users from state file", local_users.len());
+    }
 
+    let (status_tx, status_rx) = tokio::sync::watch::channel(heartbeat::AgentStatus {
+        active_count: local_users.len(),
+        xray_ok: true,
+        ..Default::default()
+    });
+    if let Some(addr) = health_addr {
+        let metrics = PrometheusBuilder::new().install_recorder()?;
+        let info = health::StaticInfo {
+            grpc_addr: grpc_addr.clone(),
+            inbound_tags: inbounds.iter().map(|i| i.tag.clone()).collect(),
+            status_token,
+        };
+        health::spawn_server(&addr, status_tx.subscribe(), metrics, info)
+            .await
+            .with_context(|| format!("AGENT_HEALTH_ADDR {}", addr))?;
+    }
+    if heartbeat_interval_secs > 0 && !dry_run {
+        tokio::spawn(heartbeat::heartbeat_loop(
+            status_rx,
+            http_client.clone(),
+            control_plane_urls.clone(),
+            server_secret.clone(),
+            Duration::from_secs(heartbeat_interval_secs),
+        ));
+    }
+
+    // Failed syncs in a row; logged loudly past the alert threshold and fatal past SYNC_EXIT_AFTER_FAILURES
+    let mut sync_failures: u32 = 0;
+
+    // Only installed with PURGE_ON_SHUTDOWN, and before the initial sync so a stop during startup
+    // still purges the users restored from the state file. Checked between sync cycles, so a
+    // cycle in progress finishes first and the purge sees every user it added.
+    let shutdown = std::sync::Arc::new(tokio::sync::Notify::new());
+    if purge_on_shutdown {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            info!("Shutdown signal received");
+            shutdown.notify_one();
+        });
+    }
+
+    // 2. Initial reconciliation. Retried until the control plane answers, so the steady-state
+    // loop only starts once Xray holds the users we're supposed to serve.
+    let initial_sync = async {
+        let mut attempts: u32 = 0;
+        loop {
+            let fetched = first_success(&control_plane_urls, "Sync", |url| {
+                fetch_full(&http_client, url, &server_secret, None, signing_key, sync_stream)
+            })
+            .await;
+            match fetched {
+                Ok(SyncResult::Full(full)) => return Ok(full),
+                Ok(SyncResult::Unchanged { .. }) => unreachable!("fetch_sync rejects a 304 we didn't ask for"),
+                Err(e) => {
+                    metrics::counter!("sync_fetch_errors_total", "kind" => "full").increment(1);
+                    attempts += 1;
+                    let retry_in = retry_after_hint(&e).unwrap_or(Duration::from_secs(INITIAL_SYNC_RETRY_SECS));
+                    warn!(
+                        "Initial sync failed ({}): {:#}. Retrying in {} seconds...",
+                        sync_error_kind(&e), e, retry_in.as_secs()
+                    );
+                    if sync_exit_after > 0 && attempts >= sync_exit_after {
+                        anyhow::bail!("giving up after {} failed initial sync attempts", attempts);
+                    }
+                    tokio::time::sleep(retry_in).await;
+                }
+            }
+        }
+    };
+    let full: SyncResponse = tokio::select! {
+        full = initial_sync => full?,
+        _ = shutdown.notified() => {
+            purge_users(&xray, &mut local_users, state_file.as_deref().filter(|_| !dry_run), purge_timeout).await;
+            return Ok(());
+        }
+    };
+    if full.users.is_empty() && !local_users.is_empty() {
+        warn!("Control plane returned no users, removing all {} restored users", local_users.len());
+    }
+    let remote_emails: HashSet<String> = full.users.iter().map(|u| u.email.clone()).collect();
+    let resynced = remove_present(&xray, &mut local_users, full.resync).await;
+    let (added, removed) = apply_full(&xray, &mut local_users, full.users).await;
+    // If-None-Match for the next full sync
+    let mut etag = etag_if_applied(full.etag, &local_users, &remote_emails);
+    info!("Initial sync done, {} users provisioned", local_users.len());
+    status_tx.send_modify(|status| {
+        status.active_count = local_users.len();
+        status.last_sync_at = Some(Utc::now());
+        status.last_cycle_added = added;
+        status.last_cycle_removed = resynced + removed;
+        status.xray_ok = !xray.needs_reconnect();
+    });
+    if let (false, Some(path)) = (dry_run, &state_file) {
+        if let Err(e) = save_state(path, &local_users) {
+            error!("Failed to write state file {}: {}", path.display(), e);
+        }
+    }
+
+    // Delta cursor from the last successful sync; None forces a full sync
+    let mut cursor: Option<String> = full.cursor;
+
+    tokio::select! {
+        _ = wait_for_next_sync(&xray, &mut local_users, &status_tx, jittered_interval(sync_interval, sync_jitter_pct)) => {}
+        _ = shutdown.notified() => {
+            purge_users(&xray, &mut local_users, state_file.as_deref().filter(|_| !dry_run), purge_timeout).await;
+            return Ok(());
+        }
+    }
+
+    let mut last_verify = tokio::time::Instant::now();
+    // Set by a reconnect: Xray may have restarted and lost the users we added over the API
+    let mut readd_pending = false;
+
+    // 3. Steady state
     loop {
-        match fetch_sync(&http_client, &control_plane_url, &server_secret).await {
-            Ok(remote_users_list) => {
-                let mut remote_map: HashMap<String, UserConfig> = HashMap::new();
-                
-                // 1. Process Additions / Updates
-                for cfg in remote_users_list {
-                    remote_map.insert(cfg.email.clone(), cfg.clone());
-                    
-                    if !local_users.contains_key(&cfg.email) {
-                        println!("Adding user: {} [Level {}]", cfg.email, cfg.level);
-                        if let Err(e) = xray.add_user(&cfg).await {
-                            eprintln!("Failed to add user {}: {}", cfg.email, e);
-                        } else {
-                            local_users.insert(cfg.email.clone(), cfg);
-                        }
-                    } 
-                    // Optional: Check if level changed and update
-                    // else if local_users[&cfg.email].level != cfg.level { ... }
-                }
-
-                // 2. Process Removals
-                // We must clone keys to iterate while modifying
-                let current_emails: Vec<String> = local_users.keys().cloned().collect();
-                for email in current_emails {
-                    if !remote_map.contains_key(&email) {
-                        println!("Removing user: {}", email);
-                        if let Err(e) = xray.remove_user(&email).await {
-                            eprintln!("Failed to remove {}: {}", email, e);
-                        } else {
-                            local_users.remove(&email);
-                        }
+        if xray.needs_reconnect() {
+            match xray.reconnect().await {
+                Ok(()) => {
+                    // Might have been a network blip, might have been an Xray restart. Either way a
+                    // full sync first drops whoever was revoked meanwhile, then the rest are re-added
+                    // (users still there are tolerated). local_users is kept so none are forgotten.
+                    cursor = None;
+                    etag = None;
+                    readd_pending = true;
+                }
+                Err(e) => {
+                    warn!("Reconnect to Xray failed: {:#}", e);
+                    status_tx.send_modify(|status| status.xray_ok = false);
+                    tokio::time::sleep(jittered_interval(sync_interval, sync_jitter_pct)).await;
+                    continue;
+                }
+            }
+        }
+
+        // A reloaded inbound comes back empty; put the users we know of back on it alone
+        let returned = xray.check_inbounds().await;
+        if !returned.is_empty() {
+            readd_users(&xray, &local_users, |i, inbound| returned.contains(&(i, inbound.tag.clone()))).await;
+        }
+
+        // Set when a failed sync came with Retry-After; replaces the interval before the next one
+        let mut retry_after: Option<Duration> = None;
+        // (added, removed) by this cycle's sync, or None when it failed
+        let synced = match cursor.take() {
+            Some(since) => match first_success(&control_plane_urls, "Sync delta", |url| {
+                fetch_delta(&http_client, url, &server_secret, &since, signing_key)
+            })
+            .await
+            {
+                Ok(DeltaResult::Changes(delta)) => {
+                    // Removals before adds, same reasoning as in apply_full
+                    let resynced = remove_present(&xray, &mut local_users, delta.resync).await;
+                    let removed = remove_present(&xray, &mut local_users, delta.removed).await;
+                    let added = add_missing(&xray, &mut local_users, delta.added).await;
+                    cursor = Some(delta.cursor);
+                    // The ETag describes the state before these changes
+                    etag = None;
+                    Some((added, resynced + removed))
+                }
+                Ok(DeltaResult::CursorRejected) => {
+                    info!("Delta cursor rejected, falling back to full sync");
+                    // Skip the sleep so we don't lose a whole interval
+                    continue;
+                }
+                Err(e) => {
+                    metrics::counter!("sync_fetch_errors_total", "kind" => "delta").increment(1);
+                    warn!("Delta sync failed ({}): {:#}", sync_error_kind(&e), e);
+                    retry_after = retry_after_hint(&e);
+                    // Keep the old cursor, the next delta covers this window too
+                    cursor = Some(since);
+                    None
+                }
+            },
+            None => match first_success(&control_plane_urls, "Sync", |url| {
+                fetch_full(&http_client, url, &server_secret, etag.as_deref(), signing_key, sync_stream)
+            })
+            .await
+            {
+                Ok(SyncResult::Unchanged { cursor: next }) => {
+                    // local_users still mirrors the last full sync, nothing to diff
+                    cursor = next;
+                    Some((0, 0))
+                }
+                Ok(SyncResult::Full(full)) => {
+                    if full.users.is_empty() && !local_users.is_empty() {
+                        warn!("Control plane returned no users, removing all {} local users", local_users.len());
                     }
+                    // Dropped here so apply_full adds them back from scratch
+                    let remote_emails: HashSet<String> = full.users.iter().map(|u| u.email.clone()).collect();
+                    let resynced = remove_present(&xray, &mut local_users, full.resync).await;
+                    let (added, removed) = apply_full(&xray, &mut local_users, full.users).await;
+                    etag = etag_if_applied(full.etag, &local_users, &remote_emails);
+                    cursor = full.cursor;
+                    if std::mem::take(&mut readd_pending) {
+                        readd_users(&xray, &local_users, |_, _| true).await;
+                    }
+                    Some((added, resynced + removed))
+                }
+                Err(e) => {
+                    metrics::counter!("sync_fetch_errors_total", "kind" => "full").increment(1);
+                    warn!("Sync failed ({}): {:#}", sync_error_kind(&e), e);
+                    retry_after = retry_after_hint(&e);
+                    None
                 }
+            },
+        };
+
+        if synced.is_some() {
+            if sync_failures >= sync_failure_alert {
+                info!("Sync recovered after {} failures", sync_failures);
+            }
+            sync_failures = 0;
+        } else {
+            sync_failures += 1;
+            if sync_failures >= sync_failure_alert {
+                error!(
+                    "Sync has failed {} times in a row, Xray users are drifting from the control plane",
+                    sync_failures
+                );
+            }
+            if sync_exit_after > 0 && sync_failures >= sync_exit_after {
+                anyhow::bail!("giving up after {} consecutive sync failures", sync_failures);
+            }
+        }
+
+        // In dry-run nothing was really added, so every user would look lost
+        if synced.is_some() && !dry_run && verify_interval_secs > 0 && last_verify.elapsed().as_secs() >= verify_interval_secs {
+            verify_present(&xray, &local_users).await;
+            last_verify = tokio::time::Instant::now();
+        }
+
+        status_tx.send_modify(|status| {
+            status.active_count = local_users.len();
+            status.xray_ok = !xray.needs_reconnect();
+            if let Some((added, removed)) = synced {
+                status.last_sync_at = Some(Utc::now());
+                status.last_cycle_added = added;
+                status.last_cycle_removed = removed;
+            }
+        });
+
+        if let (true, Some(path)) = (synced.is_some() && !dry_run, &state_file) {
+            if let Err(e) = save_state(path, &local_users) {
+                error!("Failed to write state file {}: {}", path.display(), e);
             }
-            Err(e) => eprintln!("Sync failed: {}", e),
         }
-        tokio::time::sleep(Duration::from_secs(SYNC_INTERVAL_SECS)).await;
+
+        let interval = retry_after.unwrap_or_else(|| jittered_interval(sync_interval, sync_jitter_pct));
+        tokio::select! {
+            _ = wait_for_next_sync(&xray, &mut local_users, &status_tx, interval) => {}
+            _ = shutdown.notified() => break,
+        }
+    }
+
+    purge_users(&xray, &mut local_users, state_file.as_deref().filter(|_| !dry_run), purge_timeout).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instances::XrayInstance;
+    use mock_xray::{Call, MockXray};
+
+    fn user(email: &str) -> UserConfig {
+        UserConfig {
+            uuid: format!("00000000-0000-4000-8000-{:012x}", email.len() * 4099),
+            level: 1,
+            email: email.to_string(),
+            expire_date: None,
+            target: None,
+            tags: None,
+        }
+    }
+
+    fn set(emails: &[&str]) -> HashSet<String> {
+        emails.iter().map(|e| e.to_string()).collect()
+    }
+
+    // A control plane serving `app` on a free local port; returns its base URL
+    async fn control_plane(app: axum::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    // `path` answering every request with `body` and these headers
+    async fn answering(path: &'static str, headers: Vec<(&'static str, String)>, body: String) -> String {
+        let app = axum::Router::new().route(
+            path,
+            axum::routing::get(move || async move {
+                let mut res = axum::response::IntoResponse::into_response(body);
+                for (name, value) in headers {
+                    res.headers_mut().insert(name, value.parse().unwrap());
+                }
+                res
+            }),
+        );
+        control_plane(app).await
+    }
+
+    async fn sync_serving(body: serde_json::Value) -> String {
+        answering("/api/internal/sync", vec![], body.to_string()).await
+    }
+
+    fn sign(key: &[u8], body: &[u8]) -> String {
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    async fn full_sync(url: &str) -> SyncResponse {
+        match fetch_sync(&reqwest::Client::new(), url, "secret", None, None).await.unwrap() {
+            SyncResult::Full(body) => body,
+            SyncResult::Unchanged { .. } => panic!("expected a full sync"),
+        }
+    }
+
+    async fn instances(mock: &MockXray, inbounds: &[&str]) -> XrayInstances {
+        XrayInstances {
+            instances: vec![XrayInstance {
+                name: "default".to_string(),
+                client: mock.client(inbounds).await,
+            }],
+            concurrency: 4,
+        }
+    }
+
+    #[test]
+    fn classify_status_reads_xray_messages() {
+        let cases = [
+            (tonic::Status::unknown("failed to get handler > handler not found: vless-in"), XrayFailure::InboundNotFound),
+            // "not found" in the handler message must not read as a missing user
+            (tonic::Status::not_found("handler not found: vless-in"), XrayFailure::InboundNotFound),
+            (tonic::Status::unknown("User a@x already exists."), XrayFailure::UserAlreadyExists),
+            (tonic::Status::already_exists("dup"), XrayFailure::UserAlreadyExists),
+            (tonic::Status::unknown("User a@x not found."), XrayFailure::UserNotFound),
+            (tonic::Status::unavailable("connection refused"), XrayFailure::Unavailable),
+            (tonic::Status::unknown("transport error"), XrayFailure::Unavailable),
+            (tonic::Status::deadline_exceeded("slow"), XrayFailure::Overloaded),
+            (tonic::Status::resource_exhausted("busy"), XrayFailure::Overloaded),
+            (tonic::Status::internal("boom"), XrayFailure::Other),
+        ];
+        for (status, expected) in cases {
+            assert_eq!(classify_status(&status), expected, "{:?}", status);
+        }
+        assert!(is_transient(&tonic::Status::unavailable("")));
+        assert!(!is_transient(&tonic::Status::unknown("User a@x already exists.")));
+    }
+
+    #[test]
+    fn parse_inbound_reads_tag_and_protocol() {
+        let inbound = parse_inbound("vless-in").unwrap();
+        assert_eq!((inbound.tag.as_str(), inbound.protocol), ("vless-in", Protocol::Vless));
+        let inbound = parse_inbound(" ss-in : SS ").unwrap();
+        assert_eq!((inbound.tag.as_str(), inbound.protocol), ("ss-in", Protocol::Shadowsocks));
+
+        let err = parse_inbound("wg-in:wireguard").unwrap_err();
+        assert_eq!(err.to_string(), "unsupported inbound protocol: wireguard");
+        assert!(parse_inbound("vless-in:").is_err());
+    }
+
+    #[test]
+    fn vless_flows_must_name_configured_vless_inbounds() {
+        let mut inbounds: Vec<Inbound> = ["reality-in", "tls-in", "vmess-in:vmess"]
+            .iter()
+            .map(|spec| parse_inbound(spec).unwrap())
+            .collect();
+        apply_vless_flows(&mut inbounds, "reality-in:vision, tls-in:none").unwrap();
+        assert_eq!(inbounds[0].flow.as_deref(), Some("xtls-rprx-vision"));
+        assert_eq!(inbounds[1].flow.as_deref(), Some(""));
+
+        for spec in ["reality-in", "other-in:vision", "vmess-in:vision"] {
+            assert!(apply_vless_flows(&mut inbounds, spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_reach_every_inbound() {
+        let mock = MockXray::start(&["vless-in", "trojan-in"]).await;
+        let client = mock.client(&["vless-in", "trojan-in:trojan"]).await;
+
+        client.add_user(&user("a@x")).await.unwrap();
+        assert_eq!(mock.emails("vless-in"), set(&["a@x"]));
+        assert_eq!(mock.emails("trojan-in"), set(&["a@x"]));
+
+        client.remove_user("a@x").await.unwrap();
+        assert!(mock.emails("vless-in").is_empty());
+        assert!(mock.emails("trojan-in").is_empty());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn existing_add_and_unknown_remove_count_as_success() {
+        let mock = MockXray::start(&["vless-in"]).await;
+        let client = mock.client(&["vless-in"]).await;
+        mock.put("vless-in", &["a@x"]);
+
+        client.add_user(&user("a@x")).await.unwrap();
+        client.remove_user("b@x").await.unwrap();
+        assert_eq!(mock.emails("vless-in"), set(&["a@x"]));
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let mock = MockXray::start(&["vless-in"]).await;
+        let client = mock.client(&["vless-in"]).await;
+        mock.fail_next([tonic::Status::resource_exhausted("busy")]);
+
+        client.add_user(&user("a@x")).await.unwrap();
+        assert_eq!(mock.emails("vless-in"), set(&["a@x"]));
+    }
+
+    #[tokio::test]
+    async fn other_failures_are_not_retried() {
+        let mock = MockXray::start(&["vless-in"]).await;
+        let client = mock.client(&["vless-in"]).await;
+        mock.fail_next([tonic::Status::internal("boom")]);
+
+        let err = client.add_user(&user("a@x")).await.unwrap_err();
+        assert!(err.to_string().contains("vless-in"), "{}", err);
+        // The one attempt was the failed one
+        assert!(mock.calls().is_empty());
+        assert!(mock.emails("vless-in").is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_inbound_is_skipped_until_it_returns() {
+        let mock = MockXray::start(&["vless-in", "vmess-in"]).await;
+        let client = mock.client(&["vless-in", "vmess-in:vmess"]).await;
+        client.verify_inbounds().await.unwrap();
+
+        mock.drop_inbound("vmess-in");
+        assert!(client.verify_inbounds().await.is_err());
+        client.add_user(&user("a@x")).await.unwrap();
+        assert_eq!(mock.emails("vless-in"), set(&["a@x"]));
+        assert!(client.is_inbound_missing("vmess-in"));
+
+        mock.put("vmess-in", &[]);
+        assert_eq!(client.check_inbounds().await, vec!["vmess-in".to_string()]);
+        assert!(!client.is_inbound_missing("vmess-in"));
+    }
+
+    #[tokio::test]
+    async fn apply_full_reconciles_xray_with_the_sync() {
+        let mock = MockXray::start(&["vless-in"]).await;
+        let xray = instances(&mock, &["vless-in"]).await;
+        let mut local_users = HashMap::new();
+        apply_full(&xray, &mut local_users, vec![user("a@x"), user("b@x")]).await;
+        assert_eq!(mock.emails("vless-in"), set(&["a@x", "b@x"]));
+
+        let (added, removed) = apply_full(&xray, &mut local_users, vec![user("b@x"), user("c@x")]).await;
+        assert_eq!((added, removed), (1, 1));
+        assert_eq!(mock.emails("vless-in"), set(&["b@x", "c@x"]));
+        assert_eq!(local_users.keys().cloned().collect::<HashSet<_>>(), set(&["b@x", "c@x"]));
+    }
+
+    #[tokio::test]
+    async fn apply_full_removes_a_replaced_user_before_adding_its_successor() {
+        let mock = MockXray::start(&["vless-in"]).await;
+        let xray = instances(&mock, &["vless-in"]).await;
+        mock.put("vless-in", &["user_1_abc@x"]);
+        let old = user("user_1_abc@x");
+        let mut local_users = HashMap::from([(old.email.clone(), old.clone())]);
+
+        // Plan change: same UUID, new email
+        let new = UserConfig {
+            email: "user_3_abc@x".to_string(),
+            ..old
+        };
+        let (added, removed) = apply_full(&xray, &mut local_users, vec![new]).await;
+        assert_eq!((added, removed), (1, 1));
+        assert_eq!(
+            mock.calls(),
+            vec![
+                Call::Remove { tag: "vless-in".to_string(), email: "user_1_abc@x".to_string() },
+                Call::Add { tag: "vless-in".to_string(), email: "user_3_abc@x".to_string() },
+            ]
+        );
+        assert_eq!(mock.emails("vless-in"), set(&["user_3_abc@x"]));
+    }
+
+    #[tokio::test]
+    async fn duplicated_sync_users_are_added_once() {
+        let uuid = "6f1c2a0e-4b7d-4c1e-9a55-0d3e8f7b2c11";
+        let url = sync_serving(serde_json::json!({ "users": [
+            { "uuid": uuid, "level": 1, "email": "a@x" },
+            { "uuid": uuid, "level": 1, "email": "a@x" },
+            // Same user under another email is still a duplicate
+            { "uuid": uuid, "level": 2, "email": "a2@x" },
+            { "uuid": "0b9e8d7c-6a5b-4c3d-8e2f-1a0b9c8d7e6f", "level": 1, "email": "b@x" },
+        ]}))
+        .await;
+        let body = full_sync(&url).await;
+        let emails: Vec<&str> = body.users.iter().map(|u| u.email.as_str()).collect();
+        assert_eq!(emails, vec!["a@x", "b@x"]);
+
+        let mock = MockXray::start(&["vless-in"]).await;
+        let xray = instances(&mock, &["vless-in"]).await;
+        apply_full(&xray, &mut HashMap::new(), body.users).await;
+        let adds_of_a = mock
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, Call::Add { email, .. } if email == "a@x"))
+            .count();
+        assert_eq!(adds_of_a, 1);
+    }
+
+    #[tokio::test]
+    async fn sync_skips_invalid_uuids_and_normalizes_the_rest() {
+        let url = sync_serving(serde_json::json!({ "users": [
+            { "uuid": "6F1C2A0E-4B7D-4C1E-9A55-0D3E8F7B2C11", "level": 1, "email": "upper@x" },
+            { "uuid": "not-a-uuid", "level": 1, "email": "garbage@x" },
+            { "uuid": " 0b9e8d7c6a5b4c3d8e2f1a0b9c8d7e6f ", "level": 1 },
+            { "uuid": "", "level": 1, "email": "empty@x" },
+            { "uuid": "6f1c2a0e-4b7d-4c1e-9a55-0d3e8f7b2c1", "level": 1, "email": "short@x" },
+        ]}))
+        .await;
+        let body = full_sync(&url).await;
+        let users: Vec<(&str, &str)> = body.users.iter().map(|u| (u.uuid.as_str(), u.email.as_str())).collect();
+        assert_eq!(
+            users,
+            vec![
+                ("6f1c2a0e-4b7d-4c1e-9a55-0d3e8f7b2c11", "upper@x"),
+                // The email defaults to the normalized UUID, not the raw one
+                ("0b9e8d7c-6a5b-4c3d-8e2f-1a0b9c8d7e6f", "0b9e8d7c-6a5b-4c3d-8e2f-1a0b9c8d7e6f"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_honors_retry_after() {
+        // Base URL /<retry-after>: the Retry-After to answer with, or "none"
+        let app = axum::Router::new().route(
+            "/:retry_after/api/internal/sync",
+            axum::routing::get(|axum::extract::Path(retry_after): axum::extract::Path<String>| async move {
+                let status = match retry_after.as_str() {
+                    "7" => axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                    _ => axum::http::StatusCode::TOO_MANY_REQUESTS,
+                };
+                let mut res = axum::response::IntoResponse::into_response(status);
+                if retry_after != "none" {
+                    res.headers_mut().insert("retry-after", retry_after.parse().unwrap());
+                }
+                res
+            }),
+        );
+        let url = control_plane(app).await;
+        let hint = |retry_after: &'static str| {
+            let url = format!("{}/{}", url, retry_after);
+            async move {
+                let err = fetch_sync(&reqwest::Client::new(), &url, "secret", None, None).await.err().unwrap();
+                retry_after_hint(&err)
+            }
+        };
+
+        assert_eq!(hint("7").await, Some(Duration::from_secs(7)));
+        // Capped, so a misbehaving proxy can't park the agent for hours
+        assert_eq!(hint("86400").await, Some(Duration::from_secs(MAX_RETRY_AFTER_SECS)));
+        assert_eq!(hint("none").await, None);
+        // HTTP dates aren't understood, so the regular interval applies
+        assert_eq!(hint("Wed%2C%2021%20Oct%202015%2007%3A28%3A00%20GMT").await, None);
+    }
+
+    #[tokio::test]
+    async fn sync_signatures_are_checked_when_a_key_is_set() {
+        let body = r#"{"users":[{"uuid":"6f1c2a0e-4b7d-4c1e-9a55-0d3e8f7b2c11","level":1,"email":"a@x"}]}"#;
+        let key: &[u8] = b"signing-key";
+        let cases = [
+            (Some(sign(key, body.as_bytes())), Some(key), true),
+            (Some(sign(b"other-key", body.as_bytes())), Some(key), false),
+            (Some("zz".to_string()), Some(key), false),
+            // Unsigned is as bad as forged
+            (None, Some(key), false),
+            (None, None, true),
+            (Some(sign(b"other-key", body.as_bytes())), None, true),
+        ];
+        for (signature, signing_key, ok) in cases {
+            let headers = signature.clone().map(|s| ("x-sync-signature", s)).into_iter().collect();
+            let url = answering("/api/internal/sync", headers, body.to_string()).await;
+            let result = fetch_sync(&reqwest::Client::new(), &url, "secret", None, signing_key).await;
+            assert_eq!(result.is_ok(), ok, "signature {:?}, key set: {}", signature, signing_key.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn not_modified_sync_keeps_the_users_and_moves_the_cursor() {
+        let app = axum::Router::new().route(
+            "/api/internal/sync",
+            axum::routing::get(|headers: axum::http::HeaderMap| async move {
+                assert_eq!(headers["if-none-match"], "\"abc\"");
+                (axum::http::StatusCode::NOT_MODIFIED, [("x-sync-cursor", "2026-01-01T00:00:00Z")])
+            }),
+        );
+        let url = control_plane(app).await;
+
+        match fetch_sync(&reqwest::Client::new(), &url, "secret", Some("\"abc\""), None).await.unwrap() {
+            SyncResult::Unchanged { cursor } => assert_eq!(cursor.as_deref(), Some("2026-01-01T00:00:00Z")),
+            SyncResult::Full(_) => panic!("expected 304 handling"),
+        }
+    }
+
+    #[test]
+    fn etag_is_kept_only_when_the_sync_applied_cleanly() {
+        let remote = set(&["a@x", "b@x"]);
+        let etag = || Some("\"abc\"".to_string());
+        let applied = HashMap::from([("a@x".to_string(), user("a@x")), ("b@x".to_string(), user("b@x"))]);
+        assert_eq!(etag_if_applied(etag(), &applied, &remote), etag());
+
+        // An add that failed, or a removal that did
+        let missing = HashMap::from([("a@x".to_string(), user("a@x"))]);
+        assert_eq!(etag_if_applied(etag(), &missing, &remote), None);
+        let mut extra = applied.clone();
+        extra.insert("c@x".to_string(), user("c@x"));
+        assert_eq!(etag_if_applied(etag(), &extra, &remote), None);
+    }
+
+    #[tokio::test]
+    async fn sync_stream_is_applied_only_with_its_trailer() {
+        let user_line = r#"{"uuid":"6f1c2a0e-4b7d-4c1e-9a55-0d3e8f7b2c11","level":1,"email":"a@x"}"#;
+        let trailer = |count: usize| format!(r#"{{"end":{{"cursor":"2026-01-01T00:00:00Z","resync":[],"count":{}}}}}"#, count);
+        let stream = |body: String| async move {
+            let url = answering("/api/internal/sync/stream", vec![], body).await;
+            fetch_sync_stream(&reqwest::Client::new(), &url, "secret", None).await
+        };
+
+        let complete = stream(format!("{}\n{}\n", user_line, trailer(1))).await.unwrap();
+        assert_eq!(complete.users.len(), 1);
+        assert_eq!(complete.cursor.as_deref(), Some("2026-01-01T00:00:00Z"));
+
+        // Cut off before the trailer
+        let err = stream(format!("{}\n", user_line)).await.err().unwrap();
+        assert!(err.to_string().contains("without a trailer"), "{}", err);
+        // Trailer announcing users that never came
+        assert!(stream(format!("{}\n{}\n", user_line, trailer(2))).await.is_err());
+        // Users after the trailer
+        assert!(stream(format!("{}\n{}\n{}\n", trailer(1), user_line, user_line)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn signed_sync_stream_is_verified() {
+        let key: &[u8] = b"signing-key";
+        let signed = format!(
+            "{}\n{}\n",
+            r#"{"uuid":"6f1c2a0e-4b7d-4c1e-9a55-0d3e8f7b2c11","level":1,"email":"a@x"}"#,
+            r#"{"end":{"cursor":null,"resync":[],"count":1}}"#
+        );
+        let stream = |body: String| async move {
+            let url = answering("/api/internal/sync/stream", vec![], body).await;
+            fetch_sync_stream(&reqwest::Client::new(), &url, "secret", Some(key)).await
+        };
+
+        let good = format!("{}{{\"signature\":\"{}\"}}\n", signed, sign(key, signed.as_bytes()));
+        assert!(stream(good).await.is_ok());
+        let forged = format!("{}{{\"signature\":\"{}\"}}\n", signed, sign(b"other-key", signed.as_bytes()));
+        assert!(stream(forged).await.is_err());
+        assert!(stream(signed).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_adds_stay_out_of_local_users() {
+        let mock = MockXray::start(&["vless-in"]).await;
+        let xray = instances(&mock, &["vless-in"]).await;
+        let mut local_users = HashMap::new();
+        mock.fail_next([tonic::Status::internal("boom")]);
+
+        let (added, _) = apply_full(&xray, &mut local_users, vec![user("a@x")]).await;
+        assert_eq!(added, 0);
+        assert!(local_users.is_empty());
+
+        // So the next sync tries again
+        let (added, _) = apply_full(&xray, &mut local_users, vec![user("a@x")]).await;
+        assert_eq!(added, 1);
+        assert_eq!(mock.calls(), vec![Call::Add { tag: "vless-in".to_string(), email: "a@x".to_string() }]);
+    }
+}
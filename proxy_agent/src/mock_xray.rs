@@ -0,0 +1,301 @@
+// In-process stand-in for Xray's gRPC API, for tests: the HandlerService calls XrayClient makes
+// and the StatsService calls StatsClient makes. It answers the way Xray does, failures included
+// ("handler not found", "already exists" and "not found" come back as code Unknown), and can be
+// told to fail the next alter_inbound calls with a given status.
+// Methods answer with tonic::Status as generated servers do, however large clippy finds it.
+#![allow(clippy::result_large_err)]
+
+use prost::{Message, Name};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::Status;
+
+use xray_core::app::proxyman::command::{
+    AddUserOperation, AlterInboundRequest, AlterInboundResponse, GetInboundUserRequest, GetInboundUserResponse,
+    GetInboundUsersCountResponse, RemoveUserOperation,
+};
+use xray_core::app::stats::command::{GetStatsRequest, GetStatsResponse, QueryStatsRequest, QueryStatsResponse, Stat};
+use xray_core::common::protocol::User;
+
+use crate::{AccountSettings, GrpcTarget, Inbound, VlessSettings, XrayClient};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Add { tag: String, email: String },
+    Remove { tag: String, email: String },
+}
+
+#[derive(Default)]
+struct MockState {
+    // Inbound tag -> emails on it; tags not in here answer "handler not found"
+    inbounds: HashMap<String, HashSet<String>>,
+    // Statuses the next alter_inbound calls fail with, before looking at the request
+    failures: VecDeque<Status>,
+    // Every alter_inbound that got past `failures`, in order
+    calls: Vec<Call>,
+    // Stat name -> value, e.g. "user>>>a@x>>>traffic>>>uplink"
+    stats: BTreeMap<String, i64>,
+}
+
+impl MockState {
+    fn alter_inbound(&mut self, req: AlterInboundRequest) -> Result<AlterInboundResponse, Status> {
+        if let Some(status) = self.failures.pop_front() {
+            return Err(status);
+        }
+        let tag = req.tag;
+        let Some(emails) = self.inbounds.get_mut(&tag) else {
+            return Err(Status::unknown(format!("failed to get handler > handler not found: {}", tag)));
+        };
+        let op = req.operation.ok_or_else(|| Status::invalid_argument("no operation"))?;
+        if op.r#type == AddUserOperation::full_name() {
+            let add = AddUserOperation::decode(op.value.as_slice()).map_err(|e| Status::invalid_argument(e.to_string()))?;
+            let email = add.user.map(|u| u.email).unwrap_or_default();
+            self.calls.push(Call::Add { tag, email: email.clone() });
+            if !emails.insert(email.clone()) {
+                return Err(Status::unknown(format!("User {} already exists.", email)));
+            }
+        } else if op.r#type == RemoveUserOperation::full_name() {
+            let email = RemoveUserOperation::decode(op.value.as_slice())
+                .map_err(|e| Status::invalid_argument(e.to_string()))?
+                .email;
+            self.calls.push(Call::Remove { tag, email: email.clone() });
+            if !emails.remove(&email) {
+                return Err(Status::unknown(format!("User {} not found.", email)));
+            }
+        } else {
+            return Err(Status::unknown(format!("unknown operation {}", op.r#type)));
+        }
+        Ok(AlterInboundResponse {})
+    }
+
+    fn inbound_emails(&self, tag: &str) -> Result<&HashSet<String>, Status> {
+        self.inbounds
+            .get(tag)
+            .ok_or_else(|| Status::unknown(format!("failed to get handler > handler not found: {}", tag)))
+    }
+
+    fn query_stats(&mut self, req: QueryStatsRequest) -> QueryStatsResponse {
+        let stat = self
+            .stats
+            .iter_mut()
+            .filter(|(name, _)| name.contains(&req.pattern))
+            .map(|(name, value)| Stat {
+                name: name.clone(),
+                value: if req.reset { std::mem::take(value) } else { *value },
+            })
+            .collect();
+        QueryStatsResponse { stat }
+    }
+}
+
+// Single-call gRPC method answered synchronously from the shared state
+struct Unary<F>(F);
+
+impl<Req, Res, F> UnaryService<Req> for Unary<F>
+where
+    F: FnMut(Req) -> Result<Res, Status>,
+{
+    type Response = Res;
+    type Future = std::future::Ready<Result<tonic::Response<Res>, Status>>;
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        std::future::ready((self.0)(request.into_inner()).map(tonic::Response::new))
+    }
+}
+
+trait ServiceName {
+    const NAME: &'static str;
+}
+
+struct Handler;
+impl ServiceName for Handler {
+    const NAME: &'static str = "xray.app.proxyman.command.HandlerService";
+}
+
+struct Stats;
+impl ServiceName for Stats {
+    const NAME: &'static str = "xray.app.stats.command.StatsService";
+}
+
+// Both services route on the full method path, so one implementation serves either name
+struct Api<N> {
+    state: Arc<Mutex<MockState>>,
+    name: PhantomData<N>,
+}
+
+impl<N> Clone for Api<N> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            name: PhantomData,
+        }
+    }
+}
+
+impl<N: ServiceName> NamedService for Api<N> {
+    const NAME: &'static str = N::NAME;
+}
+
+impl<N> Service<http::Request<BoxBody>> for Api<N> {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        let state = self.state.clone();
+        let path = req.uri().path().to_string();
+        Box::pin(async move {
+            let lock = || state.lock().unwrap();
+            let res = match path.as_str() {
+                "/xray.app.proxyman.command.HandlerService/AlterInbound" => {
+                    let method = Unary(|r: AlterInboundRequest| lock().alter_inbound(r));
+                    Grpc::new(ProstCodec::default()).unary(method, req).await
+                }
+                "/xray.app.proxyman.command.HandlerService/GetInboundUsers" => {
+                    let method = Unary(|r: GetInboundUserRequest| {
+                        let users = lock()
+                            .inbound_emails(&r.tag)?
+                            .iter()
+                            .map(|email| User {
+                                email: email.clone(),
+                                ..Default::default()
+                            })
+                            .collect();
+                        Ok(GetInboundUserResponse { users })
+                    });
+                    Grpc::new(ProstCodec::default()).unary(method, req).await
+                }
+                "/xray.app.proxyman.command.HandlerService/GetInboundUsersCount" => {
+                    let method = Unary(|r: GetInboundUserRequest| {
+                        let count = lock().inbound_emails(&r.tag)?.len() as i64;
+                        Ok(GetInboundUsersCountResponse { count })
+                    });
+                    Grpc::new(ProstCodec::default()).unary(method, req).await
+                }
+                "/xray.app.stats.command.StatsService/QueryStats" => {
+                    let method = Unary(|r: QueryStatsRequest| Ok(lock().query_stats(r)));
+                    Grpc::new(ProstCodec::default()).unary(method, req).await
+                }
+                "/xray.app.stats.command.StatsService/GetStatsOnline" => {
+                    let method = Unary(|r: GetStatsRequest| {
+                        let value = *lock()
+                            .stats
+                            .get(&r.name)
+                            .ok_or_else(|| Status::unknown(format!("{} not found.", r.name)))?;
+                        Ok(GetStatsResponse {
+                            stat: Some(Stat { name: r.name, value }),
+                        })
+                    });
+                    Grpc::new(ProstCodec::default()).unary(method, req).await
+                }
+                other => Status::unimplemented(format!("{} is not mocked", other)).into_http(),
+            };
+            Ok(res)
+        })
+    }
+}
+
+pub struct MockXray {
+    state: Arc<Mutex<MockState>>,
+    pub addr: String,
+}
+
+impl MockXray {
+    // Serves on a free local port until the test's runtime shuts down, with these inbounds empty
+    pub async fn start(tags: &[&str]) -> Self {
+        let state = Arc::new(Mutex::new(MockState {
+            inbounds: tags.iter().map(|tag| (tag.to_string(), HashSet::new())).collect(),
+            ..Default::default()
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind mock Xray");
+        let addr = format!("http://{}", listener.local_addr().expect("local addr"));
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(stream, _)| stream);
+            Some((conn, listener))
+        });
+        let server = tonic::transport::Server::builder()
+            .add_service(Api::<Handler> {
+                state: state.clone(),
+                name: PhantomData,
+            })
+            .add_service(Api::<Stats> {
+                state: state.clone(),
+                name: PhantomData,
+            })
+            .serve_with_incoming(incoming);
+        tokio::spawn(server);
+        Self { state, addr }
+    }
+
+    pub fn target(&self) -> GrpcTarget {
+        GrpcTarget {
+            addr: self.addr.clone(),
+            tls: None,
+            connect_timeout: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(5),
+            keepalive: Duration::from_secs(30),
+        }
+    }
+
+    // An XrayClient on `inbounds` ("tag" or "tag:protocol", as in XRAY_INBOUND_TAG)
+    pub async fn client(&self, inbounds: &[&str]) -> XrayClient {
+        let inbounds: Vec<Inbound> = inbounds
+            .iter()
+            .map(|spec| crate::parse_inbound(spec).expect("valid inbound"))
+            .collect();
+        XrayClient::new(&self.target(), inbounds, account_settings(), false)
+            .await
+            .expect("connect to mock Xray")
+    }
+
+    // The next alter_inbound calls fail with these, one each
+    pub fn fail_next(&self, statuses: impl IntoIterator<Item = Status>) {
+        self.state.lock().unwrap().failures.extend(statuses);
+    }
+
+    pub fn emails(&self, tag: &str) -> HashSet<String> {
+        self.state.lock().unwrap().inbounds.get(tag).cloned().unwrap_or_default()
+    }
+
+    // Adds users behind the agent's back, or (with an unknown tag) brings an inbound back
+    pub fn put(&self, tag: &str, emails: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        let inbound = state.inbounds.entry(tag.to_string()).or_default();
+        inbound.extend(emails.iter().map(|e| e.to_string()));
+    }
+
+    // Drops the inbound with its users, as an Xray config reload without it does
+    pub fn drop_inbound(&self, tag: &str) {
+        self.state.lock().unwrap().inbounds.remove(tag);
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    pub fn set_stat(&self, name: &str, value: i64) {
+        self.state.lock().unwrap().stats.insert(name.to_string(), value);
+    }
+}
+
+pub fn account_settings() -> AccountSettings {
+    AccountSettings {
+        vless: VlessSettings {
+            flow: "xtls-rprx-vision".to_string(),
+            encryption: "none".to_string(),
+        },
+        shadowsocks_cipher: xray_core::proxy::shadowsocks::CipherType::Aes128Gcm,
+    }
+}
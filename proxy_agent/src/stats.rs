@@ -0,0 +1,287 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tonic::transport::Channel;
+use tracing::{debug, info, warn};
+
+use xray_core::app::stats::command::{stats_service_client::StatsServiceClient, GetStatsRequest, QueryStatsRequest};
+
+// Xray names per-user counters "user>>><email>>>traffic>>>uplink|downlink".
+// Requires "StatsService" in api.services and statsUserUplink/statsUserDownlink in the policy.
+const USER_STAT_PREFIX: &str = "user>>>";
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct UserTraffic {
+    pub email: String,
+    pub uplink: i64,
+    pub downlink: i64,
+}
+
+#[derive(Serialize)]
+struct UsageReport<'a> {
+    users: &'a [UserTraffic],
+}
+
+// How many distinct IPs a user is connected from right now
+#[derive(Serialize, Debug)]
+pub struct UserOnline {
+    pub email: String,
+    pub online: i64,
+}
+
+#[derive(Serialize)]
+struct OnlineReport<'a> {
+    users: &'a [UserOnline],
+}
+
+pub struct StatsClient {
+    client: StatsServiceClient<Channel>,
+}
+
+impl StatsClient {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            client: StatsServiceClient::new(channel),
+        }
+    }
+
+    // Reads and resets every per-user traffic counter, so each call returns bytes since the previous one
+    pub async fn fetch_user_stats(&mut self) -> Result<HashMap<String, UserTraffic>> {
+        let request = QueryStatsRequest {
+            pattern: USER_STAT_PREFIX.to_string(),
+            reset: true,
+        };
+        let response = self.client.query_stats(tonic::Request::new(request)).await?;
+
+        let mut per_user: HashMap<String, UserTraffic> = HashMap::new();
+        for stat in response.into_inner().stat {
+            // user>>>EMAIL>>>traffic>>>uplink
+            let Some(rest) = stat.name.strip_prefix(USER_STAT_PREFIX) else {
+                continue;
+            };
+            let Some((email, direction)) = rest.rsplit_once(">>>traffic>>>") else {
+                continue;
+            };
+            let entry = per_user.entry(email.to_string()).or_insert_with(|| UserTraffic {
+                email: email.to_string(),
+                ..Default::default()
+            });
+            match direction {
+                "uplink" => entry.uplink += stat.value,
+                "downlink" => entry.downlink += stat.value,
+                _ => {}
+            }
+        }
+        Ok(per_user)
+    }
+
+    // Emails Xray keeps traffic counters for. Read without resetting, so the usage loop still
+    // gets its counts.
+    async fn known_emails(&mut self) -> Result<HashSet<String>> {
+        let request = QueryStatsRequest {
+            pattern: USER_STAT_PREFIX.to_string(),
+            reset: false,
+        };
+        let response = self.client.query_stats(tonic::Request::new(request)).await?;
+        Ok(response
+            .into_inner()
+            .stat
+            .into_iter()
+            .filter_map(|stat| {
+                let rest = stat.name.strip_prefix(USER_STAT_PREFIX)?;
+                rest.split_once(">>>").map(|(email, _)| email.to_string())
+            })
+            .collect())
+    }
+
+    // Online users with their IP counts, from "user>>><email>>>online". Xray only tracks these
+    // with statsUserOnline in the policy and answers "not found" for users with no connection,
+    // so without that setting every report comes out empty.
+    pub async fn fetch_online(&mut self) -> Result<Vec<UserOnline>> {
+        let mut online = Vec::new();
+        for email in self.known_emails().await? {
+            let request = GetStatsRequest {
+                name: format!("{}{}>>>online", USER_STAT_PREFIX, email),
+                reset: false,
+            };
+            match self.client.get_stats_online(tonic::Request::new(request)).await {
+                Ok(res) => {
+                    let count = res.into_inner().stat.map_or(0, |s| s.value);
+                    if count > 0 {
+                        online.push(UserOnline { email, online: count });
+                    }
+                }
+                Err(status) if status.message().contains("not found") => {}
+                Err(status) => return Err(status.into()),
+            }
+        }
+        Ok(online)
+    }
+}
+
+async fn post_online(
+    client: &reqwest::Client,
+    base_url: &str,
+    server_secret: &str,
+    users: &[UserOnline],
+) -> Result<()> {
+    let url = format!("{}/api/internal/online", base_url.trim_end_matches('/'));
+    let res = client
+        .post(&url)
+        .header("X-Server-Secret", server_secret)
+        .json(&OnlineReport { users })
+        .send()
+        .await?;
+    anyhow::ensure!(res.status().is_success(), "online report returned {}", res.status());
+    Ok(())
+}
+
+// Background task: report who is connected and from how many IPs, so the control plane can spot
+// accounts shared across more devices than their plan allows. Each report is a full snapshot;
+// a failed one is simply superseded by the next.
+pub async fn report_online_loop(
+    mut stats: Vec<StatsClient>,
+    http_client: reqwest::Client,
+    control_plane_urls: Vec<String>,
+    server_secret: String,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        // The snapshot replaces the server's previous one, so it has to cover every instance
+        let mut online = Vec::new();
+        let mut failed = false;
+        for client in &mut stats {
+            match client.fetch_online().await {
+                Ok(users) => online.extend(users),
+                Err(e) => {
+                    warn!("Failed to query Xray online stats: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if failed {
+            continue;
+        }
+        let sent = crate::first_success(&control_plane_urls, "Online report", |url| {
+            post_online(&http_client, url, &server_secret, &online)
+        })
+        .await;
+        match sent {
+            Ok(()) => debug!("Reported {} online users", online.len()),
+            Err(e) => warn!("Online report failed: {}", e),
+        }
+    }
+}
+
+async fn post_usage(
+    client: &reqwest::Client,
+    base_url: &str,
+    server_secret: &str,
+    users: &[UserTraffic],
+) -> Result<()> {
+    let url = format!("{}/api/internal/usage", base_url.trim_end_matches('/'));
+    let res = client
+        .post(&url)
+        .header("X-Server-Secret", server_secret)
+        .json(&UsageReport { users })
+        .send()
+        .await?;
+    anyhow::ensure!(res.status().is_success(), "usage report returned {}", res.status());
+    Ok(())
+}
+
+// Background task: collect per-user traffic from Xray and push it to the control plane.
+// Counters are reset on read, so anything not yet accepted by the control plane is kept and resent.
+pub async fn report_usage_loop(
+    mut stats: Vec<StatsClient>,
+    http_client: reqwest::Client,
+    control_plane_urls: Vec<String>,
+    server_secret: String,
+    interval: Duration,
+) {
+    let mut pending: HashMap<String, UserTraffic> = HashMap::new();
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        for client in &mut stats {
+            match client.fetch_user_stats().await {
+                Ok(fresh) => {
+                    for (email, traffic) in fresh {
+                        let entry = pending.entry(email).or_insert_with(|| UserTraffic {
+                            email: traffic.email.clone(),
+                            ..Default::default()
+                        });
+                        entry.uplink += traffic.uplink;
+                        entry.downlink += traffic.downlink;
+                    }
+                }
+                Err(e) => warn!("Failed to query Xray stats: {}", e),
+            }
+        }
+
+        let report: Vec<UserTraffic> = pending
+            .values()
+            .filter(|t| t.uplink > 0 || t.downlink > 0)
+            .cloned()
+            .collect();
+        if report.is_empty() {
+            continue;
+        }
+
+        let sent = crate::first_success(&control_plane_urls, "Usage report", |url| {
+            post_usage(&http_client, url, &server_secret, &report)
+        })
+        .await;
+        match sent {
+            Ok(()) => {
+                info!("Reported usage for {} users", report.len());
+                pending.clear();
+            }
+            Err(e) => warn!("Usage report failed, will retry: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_xray::MockXray;
+
+    async fn client(mock: &MockXray) -> StatsClient {
+        StatsClient::new(crate::connect_channel(&mock.target()).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn user_stats_are_read_and_reset() {
+        let mock = MockXray::start(&[]).await;
+        mock.set_stat("user>>>a@x>>>traffic>>>uplink", 10);
+        mock.set_stat("user>>>a@x>>>traffic>>>downlink", 200);
+        mock.set_stat("inbound>>>vless-in>>>traffic>>>uplink", 5);
+        let mut stats = client(&mock).await;
+
+        let first = stats.fetch_user_stats().await.unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!((first["a@x"].uplink, first["a@x"].downlink), (10, 200));
+
+        let second = stats.fetch_user_stats().await.unwrap();
+        assert_eq!((second["a@x"].uplink, second["a@x"].downlink), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn online_skips_users_without_connections() {
+        let mock = MockXray::start(&[]).await;
+        mock.set_stat("user>>>a@x>>>traffic>>>uplink", 1);
+        mock.set_stat("user>>>b@x>>>traffic>>>uplink", 1);
+        mock.set_stat("user>>>a@x>>>online", 2);
+        let mut stats = client(&mock).await;
+
+        let online = stats.fetch_online().await.unwrap();
+        assert_eq!(online.len(), 1);
+        assert_eq!((online[0].email.as_str(), online[0].online), ("a@x", 2));
+    }
+}